@@ -1,7 +1,4 @@
 use log::*;
-use space_time_trees::utils::treeviz::vizualize_tree;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
 use space_time_trees::*;
 use structopt::StructOpt;
@@ -18,22 +15,22 @@ fn handle_args() -> Args {
 }
 
 #[tokio::main]
-async fn main() -> () {
+async fn main() {
     let args = handle_args();
 
-    let buffer = Arc::new(Mutex::new(HashMap::<String, TransformStamped>::new()));
-    let buffer_clone = buffer.clone();
+    let tree = SpaceTree::new(TreeConfig::default());
+    let tree_clone = tree.clone();
     tokio::task::spawn(async move {
-        match maintain_space_tree_buffer(&buffer_clone, SPACE_TREE_BUFFER_MAINTAIN_RATE).await {
+        match tree_clone.maintain(SPACE_TREE_BUFFER_MAINTAIN_RATE).await {
             Ok(()) => (),
             Err(e) => error!("Space tree buffer maintainer failed with: '{}'.", e),
         };
     });
 
-    let buffer_clone = buffer.clone();
+    let tree_clone = tree.clone();
     if args.visualize {
         tokio::task::spawn(async move {
-            match vizualize_tree(&buffer_clone, VISUALIZE_TREE_REFRESH_RATE).await {
+            match tree_clone.visualize(VISUALIZE_TREE_REFRESH_RATE).await {
                 Ok(()) => (),
                 Err(e) => error!("Space tree buffer maintainer failed with: '{}'.", e),
             };
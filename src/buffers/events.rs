@@ -0,0 +1,224 @@
+use crate::*;
+use std::collections::HashMap;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+// An event as it is sent to the subscribers, with the ancestors of the frame at the time of the
+// change. For reparented frames the ancestors of the previous parent are included as well.
+#[derive(Debug, Clone)]
+pub(crate) struct SentEvent {
+    pub(crate) event: FrameEvent,
+    pub(crate) ancestors: Vec<String>,
+}
+
+impl SentEvent {
+    // The parents of removed frames are taken from the events of the same change,
+    // the frames are not in the tree anymore when the events are sent
+    pub(crate) fn batch(events: Vec<FrameEvent>, frames: &FrameTree, max_chain_length: u64) -> Vec<SentEvent> {
+        let parents = events
+            .iter()
+            .map(|event| (event.frame().child_frame_id.clone(), event.frame().parent_frame_id.clone()))
+            .collect::<HashMap<String, String>>();
+        let parent_of = |frame_id: &str| {
+            frames
+                .parent(frame_id)
+                .map(str::to_string)
+                .or_else(|| parents.get(frame_id).cloned())
+        };
+        events
+            .into_iter()
+            .map(|event| {
+                let mut ancestors = ancestors_of(&event.frame().parent_frame_id, &parent_of, max_chain_length);
+                if let FrameEvent::Reparented { previous_parent_frame_id, .. } = &event {
+                    ancestors.extend(ancestors_of(previous_parent_frame_id, &parent_of, max_chain_length));
+                }
+                SentEvent { event, ancestors }
+            })
+            .collect()
+    }
+}
+
+// The frame and the frames above it, up to its root or the chain length limit
+fn ancestors_of<F>(frame_id: &str, parent_of: &F, max_chain_length: u64) -> Vec<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut ancestors = vec![frame_id.to_string()];
+    while let Some(parent) = parent_of(&ancestors[ancestors.len() - 1]) {
+        if ancestors.len() as u64 > max_chain_length || ancestors.contains(&parent) {
+            break;
+        }
+        ancestors.push(parent);
+    }
+    ancestors
+}
+
+// Events of a space tree that pass the filter of the subscriber
+#[derive(Debug)]
+pub struct FrameEvents {
+    receiver: Receiver<SentEvent>,
+    filter: FrameFilter,
+}
+
+impl FrameEvents {
+    pub(crate) fn new(receiver: Receiver<SentEvent>, filter: FrameFilter) -> FrameEvents {
+        FrameEvents { receiver, filter }
+    }
+
+    // Wait for the next event that passes the filter. Subscribers that fall behind by more than
+    // TreeConfig::events_capacity events get RecvError::Lagged and continue with the oldest kept event.
+    pub async fn recv(&mut self) -> Result<FrameEvent, RecvError> {
+        loop {
+            let sent = self.receiver.recv().await?;
+            if self.passes(&sent) {
+                return Ok(sent.event);
+            }
+        }
+    }
+
+    fn passes(&self, sent: &SentEvent) -> bool {
+        let frame = sent.event.frame();
+        match &self.filter {
+            FrameFilter::All => true,
+            FrameFilter::Frames(frame_ids) => frame_ids.contains(&frame.child_frame_id),
+            // Decided by the tree as it was when the event was sent, a frame that leaves
+            // the subtree is still reported through its previous parent
+            FrameFilter::Subtree(root_frame_id) => {
+                frame.child_frame_id == *root_frame_id || sent.ancestors.contains(root_frame_id)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::collections::HashSet;
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str, time_to_live: Option<Duration>) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_events() {
+        let tree = SpaceTree::default();
+        let mut events = tree.subscribe();
+
+        tree.insert(&vec![frame("world", "table", None), frame("world", "robot", None)])
+            .await
+            .unwrap();
+        tree.insert(&vec![frame("table", "part", None)]).await.unwrap();
+        tree.insert(&vec![frame("robot", "part", None)]).await.unwrap();
+        tree.insert(&vec![frame("robot", "part", None)]).await.unwrap();
+        tree.remove("robot", RemovalMode::Cascade).unwrap();
+
+        let mut received = vec![];
+        while let Ok(sent) = events.receiver.try_recv() {
+            received.push(sent.event);
+        }
+        let kinds = received
+            .iter()
+            .map(|event| match event {
+                FrameEvent::Added(frame) => format!("added {}", frame.child_frame_id),
+                FrameEvent::Updated(frame) => format!("updated {}", frame.child_frame_id),
+                FrameEvent::Removed(frame) => format!("removed {}", frame.child_frame_id),
+                FrameEvent::Reparented { frame, previous_parent_frame_id } => {
+                    format!("reparented {} from {}", frame.child_frame_id, previous_parent_frame_id)
+                }
+                FrameEvent::Expired(frame) => format!("expired {}", frame.child_frame_id),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            kinds,
+            vec![
+                "added table",
+                "added robot",
+                "added part",
+                "reparented part from table",
+                "updated part",
+                "removed robot",
+                "removed part",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filtered_frame_events() {
+        let tree = SpaceTree::default();
+        tree.insert(&vec![frame("world", "table", None), frame("world", "robot", None)])
+            .await
+            .unwrap();
+        let mut subtree_events = tree.subscribe_filtered(FrameFilter::Subtree("robot".to_string()));
+        let mut frame_events = tree.subscribe_filtered(FrameFilter::Frames(HashSet::from(["table".to_string()])));
+
+        tree.insert(&vec![
+            frame("table", "part", None),
+            frame("robot", "gripper", None),
+            frame("gripper", "lens", None),
+        ])
+        .await
+        .unwrap();
+        tree.remove("gripper", RemovalMode::Cascade).unwrap();
+        tree.insert(&vec![frame("world", "table", None)]).await.unwrap();
+        tree.insert(&vec![frame("robot", "part", None)]).await.unwrap();
+        tree.insert(&vec![frame("table", "part", None)]).await.unwrap();
+
+        assert!(matches!(subtree_events.recv().await, Ok(FrameEvent::Added(f)) if f.child_frame_id == "gripper"));
+        assert!(matches!(subtree_events.recv().await, Ok(FrameEvent::Added(f)) if f.child_frame_id == "lens"));
+        assert!(matches!(subtree_events.recv().await, Ok(FrameEvent::Removed(f)) if f.child_frame_id == "gripper"));
+        // The lens was below the gripper, which is gone by the time the event is received
+        assert!(matches!(subtree_events.recv().await, Ok(FrameEvent::Removed(f)) if f.child_frame_id == "lens"));
+        // The part moves into the subtree and out of it again
+        assert!(matches!(subtree_events.recv().await, Ok(FrameEvent::Reparented { frame, .. }) if frame.parent_frame_id == "robot"));
+        assert!(matches!(
+            subtree_events.recv().await,
+            Ok(FrameEvent::Reparented { frame, previous_parent_frame_id }) if frame.parent_frame_id == "table" && previous_parent_frame_id == "robot"
+        ));
+        assert!(subtree_events.receiver.is_empty());
+        assert!(matches!(frame_events.recv().await, Ok(FrameEvent::Updated(f)) if f.child_frame_id == "table"));
+    }
+
+    #[tokio::test]
+    async fn test_events_capacity() {
+        let tree = SpaceTree::new(TreeConfig {
+            events_capacity: 2,
+            ..TreeConfig::default()
+        });
+        let mut events = tree.subscribe();
+        tree.insert(&vec![frame("world", "a", None), frame("world", "b", None), frame("world", "c", None)])
+            .await
+            .unwrap();
+
+        assert!(matches!(events.recv().await, Err(RecvError::Lagged(1))));
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Added(f)) if f.child_frame_id == "b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_frame_events() {
+        let tree = SpaceTree::default();
+        let mut events = tree.subscribe();
+        tree.insert(&vec![frame("world", "camera", Some(Duration::from_millis(50)))])
+            .await
+            .unwrap();
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Added(_))));
+
+        let tree_clone = tree.clone();
+        tokio::spawn(async move {
+            let _ = tree_clone.maintain(10).await;
+        });
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Expired(f)) if f.child_frame_id == "camera"));
+        assert!(!tree.contains("camera"));
+    }
+}
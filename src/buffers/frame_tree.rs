@@ -0,0 +1,163 @@
+use crate::TransformStamped;
+use im::{HashMap, OrdSet};
+use std::ops::Deref;
+
+// The latest frames keyed by their child frame id, together with an index from every parent
+// to its children. The index is updated on every insert and remove, so the tree can be walked
+// down without scanning all frames. Read access to the frames goes through Deref, changes only
+// through the methods below so that the index can't get out of sync. Both maps are persistent,
+// so cloning the tree is cheap and a clone shares all frames that didn't change since.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameTree {
+    frames: HashMap<String, TransformStamped>,
+    children: HashMap<String, OrdSet<String>>,
+}
+
+impl FrameTree {
+    pub fn new() -> FrameTree {
+        FrameTree::default()
+    }
+
+    // Insert or update the frame, moving it in the index if its parent changed
+    pub fn insert(&mut self, frame: TransformStamped) -> Option<TransformStamped> {
+        self.children
+            .entry(frame.parent_frame_id.clone())
+            .or_default()
+            .insert(frame.child_frame_id.clone());
+        let previous = self.frames.insert(frame.child_frame_id.clone(), frame);
+        if let Some(previous) = &previous {
+            if previous.parent_frame_id != self.frames[&previous.child_frame_id].parent_frame_id {
+                self.unlink(&previous.parent_frame_id, &previous.child_frame_id);
+            }
+        }
+        previous
+    }
+
+    // Remove the frame, its children stay in the tree and still point to it
+    pub fn remove(&mut self, frame_id: &str) -> Option<TransformStamped> {
+        let frame = self.frames.remove(frame_id)?;
+        self.unlink(&frame.parent_frame_id, frame_id);
+        Some(frame)
+    }
+
+    fn unlink(&mut self, parent_frame_id: &str, child_frame_id: &str) {
+        if let Some(children) = self.children.get_mut(parent_frame_id) {
+            children.remove(child_frame_id);
+            if children.is_empty() {
+                self.children.remove(parent_frame_id);
+            }
+        }
+    }
+
+    // A frame exists if it is in the tree or if some frame in the tree is its child
+    pub fn contains_frame(&self, frame_id: &str) -> bool {
+        self.frames.contains_key(frame_id) || self.children.contains_key(frame_id)
+    }
+
+    pub fn parent(&self, frame_id: &str) -> Option<&str> {
+        self.frames.get(frame_id).map(|frame| frame.parent_frame_id.as_str())
+    }
+
+    // Ids of the direct children in alphabetical order, the frame doesn't have to be in the tree
+    pub fn child_ids(&self, frame_id: &str) -> impl Iterator<Item = &String> {
+        self.children.get(frame_id).into_iter().flatten()
+    }
+
+    pub fn children(&self, frame_id: &str) -> impl Iterator<Item = &TransformStamped> {
+        self.child_ids(frame_id).filter_map(|child_id| self.frames.get(child_id))
+    }
+
+    // Frames that are parents of some frame in the tree
+    pub fn parent_ids(&self) -> impl Iterator<Item = &String> {
+        self.children.keys()
+    }
+
+    pub fn frames(&self) -> &HashMap<String, TransformStamped> {
+        &self.frames
+    }
+
+    pub fn into_frames(self) -> std::collections::HashMap<String, TransformStamped> {
+        self.frames.into_iter().collect()
+    }
+}
+
+impl Deref for FrameTree {
+    type Target = HashMap<String, TransformStamped>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.frames
+    }
+}
+
+impl FromIterator<TransformStamped> for FrameTree {
+    fn from_iter<I: IntoIterator<Item = TransformStamped>>(frames: I) -> Self {
+        let mut tree = FrameTree::new();
+        frames.into_iter().for_each(|frame| {
+            tree.insert(frame);
+        });
+        tree
+    }
+}
+
+impl From<std::collections::HashMap<String, TransformStamped>> for FrameTree {
+    fn from(frames: std::collections::HashMap<String, TransformStamped>) -> Self {
+        frames.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use tokio::time::Instant;
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_children_index_follows_changes() {
+        let mut tree = FrameTree::from_iter(vec![
+            frame("world", "table"),
+            frame("world", "robot"),
+            frame("table", "part"),
+        ]);
+        assert_eq!(tree.child_ids("world").collect::<Vec<_>>(), vec!["robot", "table"]);
+        assert!(tree.contains_frame("world"));
+        assert_eq!(tree.parent("part"), Some("table"));
+
+        // The part is picked by the robot
+        assert!(tree.insert(frame("robot", "part")).is_some());
+        assert_eq!(tree.child_ids("table").count(), 0);
+        assert_eq!(tree.children("robot").map(|f| f.child_frame_id.as_str()).collect::<Vec<_>>(), vec!["part"]);
+
+        tree.remove("part");
+        assert_eq!(tree.child_ids("robot").count(), 0);
+        assert!(!tree.contains_frame("part"));
+        assert_eq!(tree.parent_ids().collect::<Vec<_>>(), vec!["world"]);
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_is_not_affected_by_updates() {
+        let mut tree = FrameTree::from_iter(vec![frame("world", "table"), frame("table", "part")]);
+        let snapshot = tree.clone();
+
+        tree.insert(frame("world", "part"));
+        tree.remove("table");
+        assert_eq!(snapshot.parent("part"), Some("table"));
+        assert_eq!(snapshot.child_ids("table").collect::<Vec<_>>(), vec!["part"]);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(tree.len(), 1);
+    }
+}
@@ -0,0 +1,753 @@
+use crate::{
+    ExtrapolationPolicy, FrameKind, FrameTree, HistoryConfig, InterpolationPolicy, LookupError, LookupPolicy, OutOfRange,
+    TimelineSource, TransformStamped,
+};
+use nalgebra::{Isometry3, Translation3};
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+// Time ordered samples of a single frame, oldest first. Observed samples are what
+// the broadcasters reported, planned samples are where the frame is scheduled to be.
+// A removed frame keeps its samples, so that the past can still be looked up, but
+// it has no pose from its removal on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformHistory {
+    pub config: HistoryConfig,
+    samples: VecDeque<TransformStamped>,
+    planned: VecDeque<TransformStamped>,
+    removed_at: Option<Instant>,
+}
+
+impl TransformHistory {
+    pub fn new(config: HistoryConfig) -> TransformHistory {
+        TransformHistory {
+            config,
+            samples: VecDeque::new(),
+            planned: VecDeque::new(),
+            removed_at: None,
+        }
+    }
+
+    // Samples can arrive out of order, a sample with the same time stamp replaces the old one.
+    // A removed frame that gets a new sample is back in the tree.
+    pub fn insert(&mut self, frame: TransformStamped) {
+        self.removed_at = None;
+        let index = self
+            .samples
+            .partition_point(|sample| sample.time_stamp < frame.time_stamp);
+        match self.samples.get(index) {
+            Some(sample) if sample.time_stamp == frame.time_stamp => self.samples[index] = frame,
+            _ => self.samples.insert(index, frame),
+        }
+        self.prune();
+    }
+
+    // Replace the planned timeline of the frame
+    pub fn set_plan(&mut self, plan: Vec<TransformStamped>) {
+        let mut plan = plan;
+        plan.sort_by_key(|sample| sample.time_stamp);
+        plan.dedup_by_key(|sample| sample.time_stamp);
+        self.planned = plan.into();
+        self.prune();
+    }
+
+    pub fn clear_plan(&mut self) {
+        self.planned.clear();
+    }
+
+    // Planned samples are kept until they are as old as the oldest observations
+    fn prune(&mut self) {
+        while self.samples.len() > self.config.max_samples {
+            self.samples.pop_front();
+        }
+        while self.planned.len() > self.config.max_samples {
+            self.planned.pop_front();
+        }
+        if let Some(newest) = self.samples.back().map(|sample| sample.time_stamp) {
+            if let Some(horizon) = newest.checked_sub(self.config.max_duration) {
+                for samples in [&mut self.samples, &mut self.planned] {
+                    while samples
+                        .front()
+                        .is_some_and(|sample| sample.time_stamp < horizon)
+                    {
+                        samples.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn remove_at(&mut self, time: Instant) {
+        self.removed_at = Some(time);
+    }
+
+    pub fn removed_at(&self) -> Option<Instant> {
+        self.removed_at
+    }
+
+    fn is_removed_at(&self, time: Instant) -> bool {
+        self.removed_at.is_some_and(|removed_at| removed_at <= time)
+    }
+
+    // A removed frame is forgotten once its removal is older than the samples that are kept
+    pub fn is_forgotten(&self, now: Instant) -> bool {
+        self.removed_at
+            .is_some_and(|removed_at| now.saturating_duration_since(removed_at) > self.config.max_duration)
+    }
+
+    pub fn latest(&self) -> Option<&TransformStamped> {
+        self.samples.back()
+    }
+
+    pub fn oldest(&self) -> Option<&TransformStamped> {
+        self.samples.front()
+    }
+
+    // The latest sample taken at or before the requested time, none once the frame was removed
+    pub fn sample_at(&self, time: Instant) -> Option<&TransformStamped> {
+        if self.is_removed_at(time) {
+            return None;
+        }
+        match self
+            .samples
+            .partition_point(|sample| sample.time_stamp <= time)
+        {
+            0 => None,
+            index => self.samples.get(index - 1),
+        }
+    }
+
+    // Resolve the frame at the requested time, interpolating between the bracketing samples
+    // or extrapolating past the newest sample according to the policy
+    pub fn resolve_at(
+        &self,
+        time: Instant,
+        policy: &LookupPolicy,
+    ) -> Result<TransformStamped, LookupError> {
+        let timeline = self.timeline(&policy.source);
+        match self.removed_at {
+            Some(removed_at) if removed_at <= time => {
+                Err(timeline.out_of_range(time - removed_at, OutOfRange::Removed))
+            }
+            _ => timeline.resolve_at(time, policy),
+        }
+    }
+
+    // The frame this frame is attached to at the requested time on the timeline of the policy,
+    // without resolving the pose. Before the oldest sample it is the parent of the oldest sample.
+    pub fn resolve_parent_at(&self, time: Instant, policy: &LookupPolicy) -> Option<String> {
+        if self.is_removed_at(time) {
+            return None;
+        }
+        let timeline = self.timeline(&policy.source);
+        timeline
+            .get(timeline.count_until(time).saturating_sub(1))
+            .map(|sample| sample.parent_frame_id.clone())
+    }
+
+    fn timeline(&self, source: &TimelineSource) -> Timeline<'_> {
+        match source {
+            TimelineSource::Observed => Timeline {
+                frame_id: self.frame_id(),
+                head: &self.samples,
+                tail: &self.planned,
+                tail_start: self.planned.len(),
+            },
+            TimelineSource::Planned => Timeline {
+                frame_id: self.frame_id(),
+                head: &self.planned,
+                tail: &self.samples,
+                tail_start: self.samples.len(),
+            },
+            TimelineSource::PreferObserved => Timeline {
+                frame_id: self.frame_id(),
+                head: &self.samples,
+                tail: &self.planned,
+                tail_start: match self.samples.back() {
+                    Some(newest) => self
+                        .planned
+                        .partition_point(|sample| sample.time_stamp <= newest.time_stamp),
+                    None => 0,
+                },
+            },
+        }
+    }
+
+    fn frame_id(&self) -> &str {
+        match self.samples.front().or(self.planned.front()) {
+            Some(sample) => &sample.child_frame_id,
+            None => "",
+        }
+    }
+
+    // The frame this frame was attached to at the requested time
+    pub fn parent_at(&self, time: Instant) -> Option<&str> {
+        self.sample_at(time)
+            .map(|sample| sample.parent_frame_id.as_str())
+    }
+
+    // Follow a renamed frame, both where it is this frame and where it is the parent
+    pub fn rename(&mut self, frame_id: &str, new_frame_id: &str) {
+        for sample in self.samples.iter_mut().chain(self.planned.iter_mut()) {
+            if sample.child_frame_id == frame_id {
+                sample.child_frame_id = new_frame_id.to_string();
+            }
+            if sample.parent_frame_id == frame_id {
+                sample.parent_frame_id = new_frame_id.to_string();
+            }
+        }
+    }
+
+    pub fn planned(&self) -> &VecDeque<TransformStamped> {
+        &self.planned
+    }
+
+    pub fn samples(&self) -> &VecDeque<TransformStamped> {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+// A time ordered sequence of samples made of all the head samples followed by the
+// tail samples starting at tail_start, so that observations can be continued by a plan
+struct Timeline<'a> {
+    frame_id: &'a str,
+    head: &'a VecDeque<TransformStamped>,
+    tail: &'a VecDeque<TransformStamped>,
+    tail_start: usize,
+}
+
+impl Timeline<'_> {
+    fn len(&self) -> usize {
+        self.head.len() + self.tail.len() - self.tail_start
+    }
+
+    fn get(&self, index: usize) -> Option<&TransformStamped> {
+        match index.checked_sub(self.head.len()) {
+            None => self.head.get(index),
+            Some(tail_index) => self.tail.get(self.tail_start + tail_index),
+        }
+    }
+
+    // Number of samples taken at or before the requested time
+    fn count_until(&self, time: Instant) -> usize {
+        match self.head.back() {
+            Some(newest) if newest.time_stamp > time => {
+                self.head.partition_point(|sample| sample.time_stamp <= time)
+            }
+            _ => {
+                let tail_end = self.tail.partition_point(|sample| sample.time_stamp <= time);
+                self.head.len() + tail_end.max(self.tail_start) - self.tail_start
+            }
+        }
+    }
+
+    fn out_of_range(&self, gap: Duration, range: OutOfRange) -> LookupError {
+        LookupError::TimeOutOfRange {
+            frame: self.frame_id.to_string(),
+            gap,
+            range,
+        }
+    }
+
+    fn resolve_at(&self, time: Instant, policy: &LookupPolicy) -> Result<TransformStamped, LookupError> {
+        let index = self.count_until(time);
+        let before = match index.checked_sub(1).and_then(|i| self.get(i)) {
+            Some(before) => before,
+            None => {
+                return Err(match self.get(0) {
+                    Some(oldest) => self.out_of_range(oldest.time_stamp - time, OutOfRange::Past),
+                    None => self.out_of_range(Duration::ZERO, OutOfRange::NoSamples),
+                })
+            }
+        };
+        if before.time_stamp == time {
+            return Ok(before.clone());
+        }
+        match self.get(index) {
+            None => match policy.interpolation {
+                InterpolationPolicy::Exact => Err(self.out_of_range(time - before.time_stamp, OutOfRange::NotExact)),
+                _ => self.extrapolate(time, &policy.extrapolation),
+            },
+            // The frame was attached somewhere else in between, until the next sample
+            // the pose is relative to the old parent and can't be blended with the new one
+            Some(after) if after.parent_frame_id != before.parent_frame_id => {
+                match policy.interpolation {
+                    InterpolationPolicy::Exact => Err(self.out_of_range(time - before.time_stamp, OutOfRange::NotExact)),
+                    _ => Ok(before.clone()),
+                }
+            }
+            Some(after) => match policy.interpolation {
+                InterpolationPolicy::Interpolate => Ok(interpolate_samples(before, after, time)),
+                InterpolationPolicy::Nearest => {
+                    if time - before.time_stamp <= after.time_stamp - time {
+                        Ok(before.clone())
+                    } else {
+                        Ok(after.clone())
+                    }
+                }
+                InterpolationPolicy::LatestBefore => Ok(before.clone()),
+                InterpolationPolicy::Exact => Err(self.out_of_range(time - before.time_stamp, OutOfRange::NotExact)),
+            },
+        }
+    }
+
+    fn extrapolate(
+        &self,
+        time: Instant,
+        policy: &ExtrapolationPolicy,
+    ) -> Result<TransformStamped, LookupError> {
+        let len = self.len();
+        let newest = match len.checked_sub(1).and_then(|i| self.get(i)) {
+            Some(newest) => newest,
+            None => return Err(self.out_of_range(Duration::ZERO, OutOfRange::NoSamples)),
+        };
+        // Static frames don't move, their newest sample is never stale
+        if newest.frame_kind == FrameKind::Static {
+            return Ok(newest.clone());
+        }
+        let gap = time - newest.time_stamp;
+        match policy {
+            ExtrapolationPolicy::Hold => Ok(newest.clone()),
+            ExtrapolationPolicy::Reject => Err(self.out_of_range(gap, OutOfRange::Future)),
+            ExtrapolationPolicy::Extrapolate { horizon } if gap > *horizon => {
+                Err(self.out_of_range(gap, OutOfRange::BeyondHorizon(*horizon)))
+            }
+            ExtrapolationPolicy::Extrapolate { .. } => match len.checked_sub(2).and_then(|i| self.get(i)) {
+                Some(previous) if previous.parent_frame_id == newest.parent_frame_id => {
+                    Ok(extrapolate_samples(previous, newest, time))
+                }
+                // A single sample, or a sample just after a change of parent, carries no motion
+                _ => Ok(TransformStamped {
+                    time_stamp: time,
+                    ..newest.clone()
+                }),
+            },
+        }
+    }
+}
+
+// Linear interpolation of the translation and spherical linear interpolation of the rotation
+pub fn interpolate_isometry(
+    from: &Isometry3<f64>,
+    to: &Isometry3<f64>,
+    ratio: f64,
+) -> Isometry3<f64> {
+    match from.try_lerp_slerp(to, ratio, f64::EPSILON) {
+        Some(isometry) => isometry,
+        // Slerp is not well defined for rotations this close, lerp the translation only
+        None => Isometry3::from_parts(
+            from.translation
+                .vector
+                .lerp(&to.translation.vector, ratio)
+                .into(),
+            from.rotation,
+        ),
+    }
+}
+
+// The sample in between two samples of the same frame at the requested time
+pub fn interpolate_samples(
+    before: &TransformStamped,
+    after: &TransformStamped,
+    time: Instant,
+) -> TransformStamped {
+    let span = (after.time_stamp - before.time_stamp).as_secs_f64();
+    let ratio = match span > 0.0 {
+        true => (time - before.time_stamp).as_secs_f64() / span,
+        false => 0.0,
+    };
+    TransformStamped {
+        time_stamp: time,
+        transform: interpolate_isometry(&before.transform, &after.transform, ratio),
+        ..before.clone()
+    }
+}
+
+// Continue the motion between two samples of the same frame up to the requested time
+pub fn extrapolate_samples(
+    previous: &TransformStamped,
+    newest: &TransformStamped,
+    time: Instant,
+) -> TransformStamped {
+    let span = (newest.time_stamp - previous.time_stamp).as_secs_f64();
+    let ratio = match span > 0.0 {
+        true => (time - newest.time_stamp).as_secs_f64() / span,
+        false => 0.0,
+    };
+    let velocity = newest.transform.translation.vector - previous.transform.translation.vector;
+    let spin = newest.transform.rotation * previous.transform.rotation.inverse();
+    let rotation = spin.powf(ratio) * newest.transform.rotation;
+    TransformStamped {
+        time_stamp: time,
+        transform: Isometry3::from_parts(
+            Translation3::from(newest.transform.translation.vector + velocity * ratio),
+            rotation,
+        ),
+        ..newest.clone()
+    }
+}
+
+// The state of the tree as it was at the requested time, frames without data at that time are left out
+pub fn snapshot_at(
+    buffer: &HashMap<String, TransformHistory>,
+    time: Instant,
+) -> FrameTree {
+    buffer
+        .values()
+        .filter_map(|history| history.sample_at(time).cloned())
+        .collect()
+}
+
+// The latest known state of every frame in the tree, removed frames are not in it anymore
+pub fn latest_frames(buffer: &HashMap<String, TransformHistory>) -> FrameTree {
+    buffer
+        .values()
+        .filter(|history| history.removed_at().is_none())
+        .filter_map(|history| history.latest().cloned())
+        .collect()
+}
+
+// Drop the histories of the frames that were removed longer ago than their samples are kept
+pub fn forget_removed(buffer: &mut HashMap<String, TransformHistory>, now: Instant) {
+    buffer.retain(|_, history| !history.is_forgotten(now));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::{Isometry3, Vector3};
+    use std::collections::HashMap;
+    use std::f64::consts::FRAC_PI_2;
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    fn sample(time_stamp: Instant, x: f64) -> TransformStamped {
+        TransformStamped {
+            time_stamp,
+            parent_frame_id: "world".to_string(),
+            child_frame_id: "gripper".to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_history_is_time_ordered() {
+        let now = Instant::now();
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(sample(now + Duration::from_millis(20), 2.0));
+        history.insert(sample(now, 0.0));
+        history.insert(sample(now + Duration::from_millis(10), 1.0));
+
+        let xs = history
+            .samples()
+            .iter()
+            .map(|s| s.transform.translation.x)
+            .collect::<Vec<f64>>();
+        assert_eq!(xs, vec!(0.0, 1.0, 2.0));
+        assert_eq!(history.oldest().unwrap().time_stamp, now);
+        assert_eq!(
+            history.latest().unwrap().time_stamp,
+            now + Duration::from_millis(20)
+        );
+
+        // Same time stamp replaces the old sample
+        history.insert(sample(now + Duration::from_millis(10), 5.0));
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.samples()[1].transform.translation.x, 5.0);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let now = Instant::now();
+        let mut history = TransformHistory::new(HistoryConfig {
+            max_duration: Duration::from_millis(100),
+            max_samples: 5,
+        });
+
+        for i in 0..10 {
+            history.insert(sample(now + Duration::from_millis(i), i as f64));
+        }
+        assert_eq!(history.len(), 5);
+        assert_eq!(history.oldest().unwrap().transform.translation.x, 5.0);
+
+        history.insert(sample(now + Duration::from_millis(150), 150.0));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.latest().unwrap().transform.translation.x, 150.0);
+    }
+
+    #[test]
+    fn test_sample_at() {
+        let now = Instant::now();
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(sample(now + Duration::from_millis(10), 1.0));
+        history.insert(sample(now + Duration::from_millis(20), 2.0));
+
+        assert!(history.sample_at(now).is_none());
+        let at = |ms| {
+            history
+                .sample_at(now + Duration::from_millis(ms))
+                .unwrap()
+                .transform
+                .translation
+                .x
+        };
+        assert_eq!(at(10), 1.0);
+        assert_eq!(at(15), 1.0);
+        assert_eq!(at(20), 2.0);
+        assert_eq!(at(100), 2.0);
+    }
+
+    #[test]
+    fn test_resolve_at_with_policies() {
+        let now = Instant::now();
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(TransformStamped {
+            transform: Isometry3::new(Vector3::new(0.0, 0.0, 0.0), Vector3::z() * 0.0),
+            ..sample(now, 0.0)
+        });
+        history.insert(TransformStamped {
+            transform: Isometry3::new(Vector3::new(2.0, 0.0, 0.0), Vector3::z() * FRAC_PI_2),
+            ..sample(now + Duration::from_millis(100), 0.0)
+        });
+
+        let at = |ms, interpolation| {
+            history.resolve_at(
+                now + Duration::from_millis(ms),
+                &LookupPolicy {
+                    interpolation,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let res = at(25, InterpolationPolicy::Interpolate).unwrap();
+        assert_eq!(res.time_stamp, now + Duration::from_millis(25));
+        assert!((res.transform.translation.x - 0.5).abs() < 1e-9);
+        assert!((res.transform.rotation.angle() - FRAC_PI_2 / 4.0).abs() < 1e-9);
+
+        let res = at(75, InterpolationPolicy::Nearest).unwrap();
+        assert_eq!(res.transform.translation.x, 2.0);
+        let res = at(25, InterpolationPolicy::Nearest).unwrap();
+        assert_eq!(res.transform.translation.x, 0.0);
+
+        let res = at(75, InterpolationPolicy::LatestBefore).unwrap();
+        assert_eq!(res.transform.translation.x, 0.0);
+
+        assert!(at(75, InterpolationPolicy::Exact).is_err());
+        let res = at(100, InterpolationPolicy::Exact).unwrap();
+        assert_eq!(res.transform.translation.x, 2.0);
+
+        assert!(history
+            .resolve_at(now - Duration::from_millis(1), &LookupPolicy::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolve_at_with_extrapolation() {
+        let now = Instant::now();
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(TransformStamped {
+            transform: Isometry3::new(Vector3::new(0.0, 0.0, 0.0), Vector3::z() * 0.0),
+            ..sample(now, 0.0)
+        });
+        history.insert(TransformStamped {
+            transform: Isometry3::new(Vector3::new(1.0, 0.0, 0.0), Vector3::z() * 0.1),
+            ..sample(now + Duration::from_millis(100), 0.0)
+        });
+        let time = now + Duration::from_millis(150);
+
+        let at = |extrapolation| {
+            history.resolve_at(
+                time,
+                &LookupPolicy {
+                    extrapolation,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let res = at(ExtrapolationPolicy::Hold).unwrap();
+        assert_eq!(res.transform.translation.x, 1.0);
+
+        let err = at(ExtrapolationPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("'gripper'"));
+        assert!(err.to_string().contains("50ms"));
+
+        let res = at(ExtrapolationPolicy::Extrapolate {
+            horizon: Duration::from_millis(100),
+        })
+        .unwrap();
+        assert_eq!(res.time_stamp, time);
+        assert!((res.transform.translation.x - 1.5).abs() < 1e-9);
+        assert!((res.transform.rotation.angle() - 0.15).abs() < 1e-9);
+
+        assert!(at(ExtrapolationPolicy::Extrapolate {
+            horizon: Duration::from_millis(10),
+        })
+        .is_err());
+
+        // Static frames are never stale
+        let mut table = TransformHistory::new(HistoryConfig::default());
+        table.insert(TransformStamped {
+            frame_kind: FrameKind::Static,
+            ..sample(now, 2.0)
+        });
+        let res = table.resolve_at(time, &LookupPolicy::default()).unwrap();
+        assert_eq!(res.transform.translation.x, 2.0);
+    }
+
+    #[test]
+    fn test_resolve_at_with_plan() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(sample(now, 0.0));
+        history.insert(sample(now + ms(100), 1.0));
+        history.set_plan(vec![
+            sample(now + ms(300), 13.0),
+            sample(now + ms(50), 10.0),
+            sample(now + ms(200), 12.0),
+        ]);
+        assert_eq!(history.planned().len(), 3);
+
+        let at = |time, source| {
+            history
+                .resolve_at(
+                    time,
+                    &LookupPolicy {
+                        source,
+                        extrapolation: ExtrapolationPolicy::Hold,
+                        ..Default::default()
+                    },
+                )
+                .map(|res| res.transform.translation.x)
+        };
+
+        // Observed data covers the time, so it wins over the plan
+        assert_eq!(at(now + ms(50), TimelineSource::PreferObserved).unwrap(), 0.5);
+        assert_eq!(at(now + ms(50), TimelineSource::Planned).unwrap(), 10.0);
+
+        // After the newest observation the plan takes over
+        assert_eq!(at(now + ms(150), TimelineSource::PreferObserved).unwrap(), 6.5);
+        assert_eq!(at(now + ms(250), TimelineSource::PreferObserved).unwrap(), 12.5);
+        assert_eq!(at(now + ms(250), TimelineSource::Planned).unwrap(), 12.5);
+        assert_eq!(at(now + ms(250), TimelineSource::Observed).unwrap(), 1.0);
+
+        assert!(at(now + ms(10), TimelineSource::Planned).is_err());
+
+        history.clear_plan();
+        assert!(history.resolve_at(now + ms(250), &LookupPolicy::default()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_at_across_parent_change() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut history = TransformHistory::new(HistoryConfig::default());
+        history.insert(TransformStamped {
+            parent_frame_id: "table".to_string(),
+            ..sample(now, 0.0)
+        });
+        history.insert(TransformStamped {
+            parent_frame_id: "gripper".to_string(),
+            ..sample(now + ms(100), 5.0)
+        });
+
+        assert_eq!(history.parent_at(now - ms(1)), None);
+        assert_eq!(history.parent_at(now + ms(50)), Some("table"));
+        assert_eq!(history.parent_at(now + ms(100)), Some("gripper"));
+
+        for interpolation in [InterpolationPolicy::Interpolate, InterpolationPolicy::Nearest] {
+            let res = history
+                .resolve_at(
+                    now + ms(90),
+                    &LookupPolicy {
+                        interpolation,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            assert_eq!(res.parent_frame_id, "table");
+            assert_eq!(res.transform.translation.x, 0.0);
+        }
+
+        // No motion is carried over from the old parent
+        let res = history
+            .resolve_at(
+                now + ms(150),
+                &LookupPolicy {
+                    extrapolation: ExtrapolationPolicy::Extrapolate { horizon: ms(100) },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(res.parent_frame_id, "gripper");
+        assert_eq!(res.transform.translation.x, 5.0);
+    }
+
+    #[test]
+    fn test_snapshot_at() {
+        let now = Instant::now();
+        let mut gripper = TransformHistory::new(HistoryConfig::default());
+        gripper.insert(sample(now, 0.0));
+        gripper.insert(sample(now + Duration::from_millis(10), 1.0));
+        let mut camera = TransformHistory::new(HistoryConfig::default());
+        camera.insert(TransformStamped {
+            child_frame_id: "camera".to_string(),
+            ..sample(now + Duration::from_millis(10), 3.0)
+        });
+        let buffer = HashMap::from([
+            ("gripper".to_string(), gripper),
+            ("camera".to_string(), camera),
+        ]);
+
+        let snapshot = snapshot_at(&buffer, now + Duration::from_millis(5));
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot.get("gripper").unwrap().transform.translation.x, 0.0);
+
+        let latest = latest_frames(&buffer);
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest.get("gripper").unwrap().transform.translation.x, 1.0);
+    }
+
+    #[test]
+    fn test_removed_history() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut gripper = TransformHistory::new(HistoryConfig::default());
+        gripper.insert(sample(now, 0.0));
+        gripper.remove_at(now + ms(10));
+        let hold = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..Default::default()
+        };
+
+        // The past is still there, but the frame has no pose after its removal
+        assert_eq!(gripper.resolve_at(now + ms(5), &hold).unwrap().transform.translation.x, 0.0);
+        assert!(matches!(
+            gripper.resolve_at(now + ms(20), &hold),
+            Err(LookupError::TimeOutOfRange { range: OutOfRange::Removed, .. })
+        ));
+        assert_eq!(gripper.sample_at(now + ms(20)), None);
+        let mut buffer = HashMap::from([("gripper".to_string(), gripper)]);
+        assert!(latest_frames(&buffer).is_empty());
+
+        // Once the removal is older than the kept samples the history goes away
+        forget_removed(&mut buffer, now + ms(10) + HistoryConfig::default().max_duration);
+        assert_eq!(buffer.len(), 1);
+        forget_removed(&mut buffer, now + ms(11) + HistoryConfig::default().max_duration);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1 +1,5 @@
-pub mod space_tree;
\ No newline at end of file
+pub mod frame_tree;
+pub mod space_tree;
+pub mod history;
+pub mod storage;
+pub mod events;
\ No newline at end of file
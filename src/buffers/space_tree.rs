@@ -1,32 +1,582 @@
-use crate::TransformStamped;
+use crate::*;
+use log::{error, warn};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{broadcast, watch};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::time::{Duration, Instant};
 
+// One handle to the whole tree: the latest frames and the history of every frame.
+// Cloning the handle is cheap and all clones share the same state. Lookups always use the
+// in-memory buffers, changes are also written through to the storage if the tree has one.
+#[derive(Debug, Clone)]
+pub struct SpaceTree {
+    frames: Arc<RwLock<FrameTree>>,
+    history: Arc<RwLock<HashMap<String, TransformHistory>>>,
+    config: TreeConfig,
+    storage: Option<Arc<dyn FrameStorage>>,
+    events: broadcast::Sender<SentEvent>,
+    // Plans don't change the latest frames, but lookups waiting for a planned time need to know
+    plans: Arc<watch::Sender<()>>,
+}
+
+impl Default for SpaceTree {
+    fn default() -> Self {
+        SpaceTree::new(TreeConfig::default())
+    }
+}
+
+impl SpaceTree {
+    pub fn new(config: TreeConfig) -> SpaceTree {
+        SpaceTree {
+            frames: Arc::default(),
+            history: Arc::default(),
+            events: broadcast::channel(config.events_capacity).0,
+            config,
+            storage: None,
+            plans: Arc::new(watch::channel(()).0),
+        }
+    }
+
+    pub fn config(&self) -> &TreeConfig {
+        &self.config
+    }
+
+    // A tree that starts with the frames already in the storage and writes its changes to it.
+    // The stored frames are checked like the frames of a scenario, whoever wrote them.
+    pub fn with_storage(
+        config: TreeConfig,
+        storage: Arc<dyn FrameStorage>,
+    ) -> Result<SpaceTree, ErrorMsg> {
+        let frames = Arc::new(RwLock::new(FrameTree::new()));
+        let report = insert_frames(&frames, &storage.frames()?, &config, true)
+            .map_err(|e| ErrorMsg::new(&e.to_string()))?;
+        let mut history = HashMap::new();
+        for event in &report.accepted {
+            let frame = event.frame();
+            let mut frame_history = TransformHistory::new(config.history);
+            for sample in storage.history(&frame.child_frame_id)? {
+                frame_history.insert(sample);
+            }
+            frame_history.insert(frame.clone());
+            history.insert(frame.child_frame_id.clone(), frame_history);
+        }
+        Ok(SpaceTree {
+            frames,
+            history: Arc::new(RwLock::new(history)),
+            storage: Some(storage),
+            ..SpaceTree::new(config)
+        })
+    }
+
+    // Add or update frames, both the latest frames and their history are updated.
+    // Invalid frames are rejected, see add_frames.
+    pub async fn insert(&self, frames: &Vec<TransformStamped>) -> Result<InsertReport, Box<dyn std::error::Error>> {
+        let report = add_frames(&self.frames, frames, &self.config).await?;
+        self.write_through(report).await
+    }
+
+    // Add or update the frames of a scenario, static frames can only be changed this way
+    pub async fn load_scenario(&self, frames: &Vec<TransformStamped>) -> Result<InsertReport, Box<dyn std::error::Error>> {
+        let report = add_scenario_frames(&self.frames, frames, &self.config).await?;
+        self.write_through(report).await
+    }
+
+    // Record the accepted frames in the history and the storage and tell the subscribers.
+    // The frames were checked against the latest frames, the history takes all of them.
+    // A frame the storage fails to take is reported but stays applied, the other frames
+    // are still written through and every applied frame is announced.
+    async fn write_through(&self, mut report: InsertReport) -> Result<InsertReport, Box<dyn std::error::Error>> {
+        let accepted = report
+            .accepted
+            .iter()
+            .map(|event| event.frame().clone())
+            .collect::<Vec<TransformStamped>>();
+        // Late samples only go into the history, the storage keeps the latest frames
+        let samples = accepted.iter().chain(&report.late).cloned().collect::<Vec<TransformStamped>>();
+        record_samples(&self.history, &samples, &self.config);
+        report.unstored = self.store(&[], &accepted);
+        self.notify(report.accepted.clone());
+        Ok(report)
+    }
+
+    // Write the changes through to the storage, if the tree has one. Every change is attempted,
+    // the ones the storage fails to take are returned with the reason.
+    fn store(&self, removed: &[TransformStamped], changed: &[TransformStamped]) -> Vec<(TransformStamped, String)> {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return vec![],
+        };
+        let removals = removed
+            .iter()
+            .map(|frame| (frame, storage.remove(&frame.child_frame_id).map(|_| ())));
+        let puts = changed.iter().map(|frame| (frame, storage.put(frame)));
+        removals
+            .chain(puts)
+            .filter_map(|(frame, result)| match result {
+                Ok(()) => None,
+                Err(e) => {
+                    error!("Frame '{}' could not be written to the storage: '{}'", frame.child_frame_id, e);
+                    Some((frame.clone(), e.to_string()))
+                }
+            })
+            .collect()
+    }
+
+    // Attach the frame to a new parent, see reparent_frame. The change is recorded in the history,
+    // written to the storage and announced, the tree stays changed if the storage fails.
+    pub fn reparent(
+        &self,
+        frame_id: &str,
+        new_parent_frame_id: &str,
+        keep_world_pose: bool,
+    ) -> Result<ChangeReport, ErrorMsg> {
+        let previous = self.frames.read().unwrap().get(frame_id).cloned();
+        let reparented = reparent_frame(
+            frame_id,
+            new_parent_frame_id,
+            keep_world_pose,
+            &self.config.names.root,
+            &self.frames,
+            &self.config,
+        )?;
+        self.history
+            .write()
+            .unwrap()
+            .entry(frame_id.to_string())
+            .or_insert_with(|| TransformHistory::new(self.config.history))
+            .insert(reparented.clone());
+        Ok(self.announce_reparent(previous.as_ref(), reparented))
+    }
+
+    // Attach the frame to a new parent from the requested time on, see reparent_frame_at.
+    // The latest frames only change when the new sample is the newest of the frame.
+    pub fn reparent_at(
+        &self,
+        frame_id: &str,
+        new_parent_frame_id: &str,
+        keep_world_pose: bool,
+        time: Instant,
+        policy: &LookupPolicy,
+    ) -> Result<ChangeReport, ErrorMsg> {
+        let previous = self.frames.read().unwrap().get(frame_id).cloned();
+        let reparented = reparent_frame_at(
+            frame_id,
+            new_parent_frame_id,
+            keep_world_pose,
+            &self.config.names.root,
+            time,
+            policy,
+            &self.history,
+            &self.config,
+        )?;
+        let is_latest = self
+            .history
+            .read()
+            .unwrap()
+            .get(frame_id)
+            .and_then(|history| history.latest())
+            == Some(&reparented);
+        if is_latest {
+            self.frames.write().unwrap().insert(reparented.clone());
+            Ok(self.announce_reparent(previous.as_ref(), reparented))
+        } else {
+            Ok(ChangeReport {
+                frame: reparented,
+                unstored: vec![],
+            })
+        }
+    }
+
+    fn announce_reparent(&self, previous: Option<&TransformStamped>, frame: TransformStamped) -> ChangeReport {
+        self.notify(vec![change_event(previous, frame.clone())]);
+        ChangeReport {
+            unstored: self.store(&[], std::slice::from_ref(&frame)),
+            frame,
+        }
+    }
+
+    // Replace the planned timelines of the frames, see set_frame_plans
+    pub async fn set_plans(&self, plan: &Vec<TransformStamped>) -> Result<PlanReport, Box<dyn std::error::Error>> {
+        let report = plan_frames(&self.history, plan, &self.frames.read().unwrap(), &self.config);
+        self.plans.send_replace(());
+        Ok(report)
+    }
+
+    // Remove the frame, see remove_frame. The history of a removed frame is kept until
+    // the history config prunes it, so that lookups before the removal still work.
+    pub fn remove(&self, frame_id: &str, mode: RemovalMode) -> Result<RemovalReport, ErrorMsg> {
+        let mut report = remove_frame(frame_id, mode, &self.frames)?;
+        let now = Instant::now();
+        let mut history_local = self.history.write().unwrap();
+        for frame in &report.removed {
+            if let Some(history) = history_local.get_mut(&frame.child_frame_id) {
+                history.remove_at(now);
+            }
+        }
+        // The adopted children keep their time stamps like in the latest frames,
+        // their older samples stay as they were
+        for frame in &report.reparented {
+            if let Some(history) = history_local.get_mut(&frame.child_frame_id) {
+                history.insert(frame.clone());
+            }
+        }
+        forget_removed(&mut history_local, now);
+        drop(history_local);
+        // The subscribers hear about the change even if the storage fails
+        self.notify(
+            report
+                .removed
+                .iter()
+                .map(|frame| FrameEvent::Removed(frame.clone()))
+                .chain(report.reparented.iter().map(|frame| FrameEvent::Reparented {
+                    frame: frame.clone(),
+                    previous_parent_frame_id: frame_id.to_string(),
+                }))
+                .collect(),
+        );
+        report.unstored = self.store(&report.removed, &report.reparented);
+        Ok(report)
+    }
+
+    // Rename the frame in the latest frames, the history and the storage, see rename_frame.
+    // Subscribers see the old frame removed, the new one added and its children reparented.
+    pub fn rename(&self, frame_id: &str, new_frame_id: &str) -> Result<ChangeReport, ErrorMsg> {
+        let renamed = rename_frame(frame_id, new_frame_id, &self.frames, &self.config.names)?;
+        let children = self
+            .frames
+            .read()
+            .unwrap()
+            .children(new_frame_id)
+            .cloned()
+            .collect::<Vec<TransformStamped>>();
+
+        let mut history_local = self.history.write().unwrap();
+        if let Some(mut history) = history_local.remove(frame_id) {
+            history.rename(frame_id, new_frame_id);
+            history_local.insert(new_frame_id.to_string(), history);
+        }
+        for child in &children {
+            if let Some(history) = history_local.get_mut(&child.child_frame_id) {
+                history.rename(frame_id, new_frame_id);
+            }
+        }
+        let samples = history_local
+            .get(new_frame_id)
+            .map(|history| history.samples().iter().cloned().collect::<Vec<TransformStamped>>())
+            .unwrap_or_default();
+        drop(history_local);
+
+        let removed = TransformStamped {
+            child_frame_id: frame_id.to_string(),
+            ..renamed.clone()
+        };
+        self.notify(
+            [FrameEvent::Removed(removed.clone()), FrameEvent::Added(renamed.clone())]
+                .into_iter()
+                .chain(children.iter().map(|child| FrameEvent::Reparented {
+                    frame: child.clone(),
+                    previous_parent_frame_id: frame_id.to_string(),
+                }))
+                .collect(),
+        );
+        let changed = samples.into_iter().chain(children).collect::<Vec<TransformStamped>>();
+        Ok(ChangeReport {
+            unstored: self.store(&[removed], &changed),
+            frame: renamed,
+        })
+    }
+
+    // Changes made by other trees sharing the storage, they are already stored and are not written back.
+    // They are checked like the frames of the broadcasters, other trees may have other limits.
+    // A static frame comes from a scenario loaded by another tree, only a dynamic frame
+    // can't overwrite a static one.
+    pub(crate) fn apply_stored_frame(&self, frame: TransformStamped) {
+        let scenario = frame.frame_kind == FrameKind::Static;
+        let report = match insert_frames(&self.frames, &vec![frame], &self.config, scenario) {
+            Ok(report) => report,
+            Err(_) => return,
+        };
+        let samples = report
+            .accepted
+            .iter()
+            .map(|event| event.frame().clone())
+            .chain(report.late)
+            .collect::<Vec<TransformStamped>>();
+        record_samples(&self.history, &samples, &self.config);
+        self.notify(report.accepted);
+    }
+
+    pub(crate) fn apply_stored_removal(&self, frame_id: &str) {
+        self.mark_removed(frame_id);
+        let removed = self.frames.write().unwrap().remove(frame_id);
+        if let Some(frame) = removed {
+            self.notify(vec![FrameEvent::Removed(frame)]);
+        }
+    }
+
+    // Events for all changes of the latest frames
+    pub fn subscribe(&self) -> FrameEvents {
+        self.subscribe_filtered(FrameFilter::All)
+    }
+
+    pub fn subscribe_filtered(&self, filter: FrameFilter) -> FrameEvents {
+        FrameEvents::new(self.events.subscribe(), filter)
+    }
 
-// TODO: actually, this should just remove stale frames, the time stamps should be updated by the broadcasters
+    // The events of one change are sent together, so that the subtree of every frame is known
+    // even when its parent went away in the same change. Nobody listening is not an error.
+    fn notify(&self, events: Vec<FrameEvent>) {
+        let sent = SentEvent::batch(events, &self.frames.read().unwrap(), self.config.max_chain_length);
+        for sent_event in sent {
+            let _ = self.events.send(sent_event);
+        }
+    }
+
+    // Pose of the source frame in the target frame using the latest frames
+    pub fn lookup(&self, target_frame_id: &str, source_frame_id: &str) -> Result<TransformStamped, LookupError> {
+        lookup_transform_lca(target_frame_id, source_frame_id, &self.frames, &self.config)
+    }
+
+    // Pose of the source frame in the target frame as it was (or is planned to be) at the requested time
+    pub fn lookup_at(
+        &self,
+        target_frame_id: &str,
+        source_frame_id: &str,
+        time: Instant,
+        policy: &LookupPolicy,
+    ) -> Result<TransformStamped, LookupError> {
+        lookup_transform_lca_at(target_frame_id, source_frame_id, time, policy, &self.history, &self.config)
+    }
+
+    // Pose of the source frame at the source time in the target frame at the target time,
+    // assuming that the fixed frame doesn't move in between, see lookup_transform_full
+    pub fn lookup_full(
+        &self,
+        target_frame_id: &str,
+        target_time: Instant,
+        source_frame_id: &str,
+        source_time: Instant,
+        fixed_frame_id: &str,
+        policy: &LookupPolicy,
+    ) -> Result<TransformStamped, LookupError> {
+        lookup_transform_full(
+            target_frame_id,
+            target_time,
+            source_frame_id,
+            source_time,
+            fixed_frame_id,
+            &self.config.names.root,
+            policy,
+            &self.history,
+            &self.config,
+        )
+    }
+
+    // Wait until the lookup succeeds, it is retried whenever the tree changes.
+    // If it still fails after the timeout, the error says why.
+    pub async fn wait_for_transform(
+        &self,
+        target_frame_id: &str,
+        source_frame_id: &str,
+        timeout: Duration,
+    ) -> Result<TransformStamped, LookupError> {
+        self.wait_for(|| self.lookup(target_frame_id, source_frame_id), timeout)
+            .await
+    }
+
+    // Same as wait_for_transform, but waits until the lookup at the requested time succeeds,
+    // e.g. until samples newer than the requested time arrive when extrapolation is rejected
+    pub async fn wait_for_transform_at(
+        &self,
+        target_frame_id: &str,
+        source_frame_id: &str,
+        time: Instant,
+        policy: &LookupPolicy,
+        timeout: Duration,
+    ) -> Result<TransformStamped, LookupError> {
+        self.wait_for(
+            || self.lookup_at(target_frame_id, source_frame_id, time, policy),
+            timeout,
+        )
+        .await
+    }
+
+    async fn wait_for<F>(&self, lookup: F, timeout: Duration) -> Result<TransformStamped, LookupError>
+    where
+        F: Fn() -> Result<TransformStamped, LookupError>,
+    {
+        // Subscribed before the first try, so that no change in between is missed
+        let mut events = self.events.subscribe();
+        let mut plans = self.plans.subscribe();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let result = lookup();
+            let changed = async {
+                tokio::select! {
+                    _ = events.recv() => (),
+                    _ = plans.changed() => (),
+                }
+            };
+            if result.is_ok() || tokio::time::timeout_at(deadline, changed).await.is_err() {
+                return result;
+            }
+        }
+    }
+
+    pub fn children(&self, frame_id: &str) -> Vec<TransformStamped> {
+        self.frames.read().unwrap().children(frame_id).cloned().collect()
+    }
+
+    pub fn contains(&self, frame_id: &str) -> bool {
+        self.frames.read().unwrap().contains_frame(frame_id)
+    }
+
+    // Roots of the independent trees in the forest, see find_roots
+    pub fn roots(&self) -> Vec<String> {
+        find_roots(&self.frames.read().unwrap())
+    }
+
+    // The frames of every independent tree, starting with its root
+    pub fn components(&self) -> Vec<Vec<String>> {
+        find_components(&self.frames.read().unwrap())
+    }
+
+    // The root of the tree the frame is in
+    pub fn root_of(&self, frame_id: &str) -> Option<String> {
+        find_root(frame_id, &self.frames.read().unwrap())
+    }
+
+    // Snapshot of the latest frames below the root
+    pub fn component(&self, root: &str) -> FrameTree {
+        component_tree(root, &self.frames.read().unwrap())
+    }
+
+    // All cycles among the latest frames, see find_cycles
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        find_cycles(&self.frames.read().unwrap())
+    }
+
+    // Check that the latest frames form a tree, or a forest
+    pub fn validate(&self) -> Result<(), LookupError> {
+        match self.cycles().into_iter().next() {
+            Some(cycle) => Err(LookupError::CycleDetected(cycle)),
+            None => Ok(()),
+        }
+    }
+
+    // The latest frames, later changes to the tree don't affect the snapshot
+    pub fn snapshot(&self) -> FrameTree {
+        self.frames.read().unwrap().clone()
+    }
+
+    // The frames as they were at the requested time
+    pub fn snapshot_at(&self, time: Instant) -> FrameTree {
+        snapshot_at(&self.history.read().unwrap(), time)
+    }
+
+    // Evict the stale frames, see maintain_space_tree_buffer. Evicted frames are
+    // reported to the subscribers as expired.
+    pub async fn maintain(&self, maintain_rate: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let (sender, mut receiver) = unbounded_channel::<Vec<TransformStamped>>();
+        let forward = async {
+            while let Some(evicted) = receiver.recv().await {
+                evicted.iter().for_each(|frame| self.forget_evicted(frame));
+                self.notify(evicted.into_iter().map(FrameEvent::Expired).collect());
+            }
+        };
+        // The error is converted so that the future can be sent between threads
+        let maintain = async {
+            maintain_space_tree_buffer(&self.frames, maintain_rate, Some(sender))
+                .await
+                .map_err(|e| ErrorMsg::new(&e.to_string()))
+        };
+        let (result, _) = tokio::join!(maintain, forward);
+        Ok(result?)
+    }
+
+    // An evicted frame is removed from the history and the storage as well,
+    // unless a broadcaster brought it back in the meantime
+    fn forget_evicted(&self, frame: &TransformStamped) {
+        let frames_local = self.frames.read().unwrap();
+        if frames_local.contains_key(&frame.child_frame_id) {
+            return;
+        }
+        self.mark_removed(&frame.child_frame_id);
+        drop(frames_local);
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.remove(&frame.child_frame_id) {
+                warn!("Evicted frame '{}' could not be removed from the storage: '{}'", frame.child_frame_id, e);
+            }
+        }
+    }
+
+    // The samples of a frame that left the tree are kept until the history config prunes them
+    fn mark_removed(&self, frame_id: &str) {
+        let now = Instant::now();
+        let mut history_local = self.history.write().unwrap();
+        if let Some(history) = history_local.get_mut(frame_id) {
+            history.remove_at(now);
+        }
+        forget_removed(&mut history_local, now);
+    }
+
+    pub async fn visualize(&self, refresh_rate: u64) -> Result<(), Box<dyn std::error::Error>> {
+        vizualize_tree(&self.frames, refresh_rate, &self.config).await
+    }
+}
+
+// A frame is stale when its last update is older than its time to live.
+// Static frames and frames without a time to live never become stale.
+pub fn is_stale(frame: &TransformStamped, now: Instant) -> bool {
+    match (frame.frame_kind, frame.time_to_live) {
+        (FrameKind::Static, _) => false,
+        (_, Some(time_to_live)) => now.saturating_duration_since(frame.time_stamp) > time_to_live,
+        (_, None) => false,
+    }
+}
+
+// Remove stale frames from the buffer and return them
+pub fn evict_stale_frames(
+    buffer: &mut FrameTree,
+    now: Instant,
+) -> Vec<TransformStamped> {
+    let stale = buffer
+        .iter()
+        .filter(|(_, frame)| is_stale(frame, now))
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<String>>();
+
+    stale
+        .iter()
+        .filter_map(|name| buffer.remove(name))
+        .collect()
+}
+
+// The time stamps are updated by the broadcasters, this only removes the frames that
+// stopped being updated. Evicted frames are reported on the channel if one is given.
 pub async fn maintain_space_tree_buffer(
-    buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
+    buffer: &Arc<RwLock<FrameTree>>,
     maintain_rate: u64,
+    evicted_sender: Option<UnboundedSender<Vec<TransformStamped>>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     loop {
-        let buffer_local = buffer.lock().unwrap().clone();
-        let mut updated_buffer = HashMap::new();
-
-        buffer_local.iter().for_each(|(name, frame)| {
-            let _insert_res = updated_buffer.insert(
-                name.to_string(),
-                TransformStamped {
-                    time_stamp: Instant::now(),
-                    parent_frame_id: frame.parent_frame_id.clone(),
-                    child_frame_id: frame.child_frame_id.clone(),
-                    transform: frame.transform,
-                    json_metadata: frame.json_metadata.clone(),
-                },
-            );
-        });
+        let evicted = evict_stale_frames(&mut buffer.write().unwrap(), Instant::now());
+
+        if !evicted.is_empty() {
+            evicted.iter().for_each(|frame| {
+                warn!(
+                    "Frame '{}' was not updated for more than {:?} and is evicted",
+                    frame.child_frame_id,
+                    frame.time_to_live.unwrap_or_default()
+                )
+            });
+            // Nobody listening for evicted frames is no reason to stop evicting them
+            if let Some(sender) = &evicted_sender {
+                let _ = sender.send(evicted);
+            }
+        }
 
-        *buffer.lock().unwrap() = updated_buffer;
         tokio::time::sleep(Duration::from_millis(maintain_rate)).await;
     }
 }
@@ -34,9 +584,9 @@ pub async fn maintain_space_tree_buffer(
 #[cfg(test)]
 mod tests {
 
-    use nalgebra::Isometry3;
+    use nalgebra::{Isometry3, Vector3};
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, RwLock};
     use tokio::time::{interval, timeout, Duration, Instant};
 
     use crate::*;
@@ -80,75 +630,504 @@ mod tests {
         assert_eq!(counter_assert, 301)
     }
 
+    fn frame(name: &str, time_to_live: Option<Duration>) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            child_frame_id: name.to_string(),
+            parent_frame_id: "parent".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: "{foo: bar}".to_string(),
+            time_to_live,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_evict_stale_frames() {
+        let now = Instant::now();
+        let mut buffer = FrameTree::from(HashMap::from([
+            ("static".to_string(), frame("static", None)),
+            ("fresh".to_string(), frame("fresh", Some(Duration::from_millis(500)))),
+            ("stale".to_string(), frame("stale", Some(Duration::from_millis(50)))),
+        ]));
+
+        let evicted = evict_stale_frames(&mut buffer, now + Duration::from_millis(100));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].child_frame_id, "stale");
+        assert!(buffer.contains_key("static"));
+        assert!(buffer.contains_key("fresh"));
+
+        let evicted = evict_stale_frames(&mut buffer, now + Duration::from_secs(3600));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].child_frame_id, "fresh");
+        assert!(buffer.contains_key("static"));
+    }
+
+    #[test]
+    fn test_static_frames_are_never_stale() {
+        let now = Instant::now();
+        let frame = TransformStamped {
+            frame_kind: FrameKind::Static,
+            ..frame("table", Some(Duration::from_millis(50)))
+        };
+        assert!(!is_stale(&frame, now + Duration::from_secs(3600)));
+        let frame = TransformStamped {
+            frame_kind: FrameKind::Dynamic,
+            ..frame
+        };
+        assert!(is_stale(&frame, now + Duration::from_secs(3600)));
+    }
+
     #[tokio::test(start_paused = true)]
     async fn space_tree_buffer_is_maintained() {
-        let mut interval = interval(Duration::from_millis(50));
-
-        let test_buffer = HashMap::from([
+        let test_buffer = FrameTree::from(HashMap::from([
+            ("static".to_string(), frame("static", None)),
             (
-                "test_transform".to_string(),
-                TransformStamped {
-                    time_stamp: Instant::now(),
-                    child_frame_id: "child_1".to_string(),
-                    parent_frame_id: "parent".to_string(),
-                    transform: Isometry3::default(),
-                    json_metadata: "{foo: bar}".to_string(),
-                },
+                "camera".to_string(),
+                frame("camera", Some(Duration::from_millis(100))),
             ),
             (
-                "test_transform_2".to_string(),
-                TransformStamped {
-                    time_stamp: Instant::now(),
-                    child_frame_id: "child_2".to_string(),
-                    parent_frame_id: "parent".to_string(),
-                    transform: Isometry3::default(),
-                    json_metadata: "{foo: bar}".to_string(),
-                },
+                "gripper".to_string(),
+                frame("gripper", Some(Duration::from_millis(100))),
             ),
-        ]);
-
-        let mut acc_vec = vec![];
-        acc_vec.push(test_buffer.clone());
+        ]));
 
-        let buffer = Arc::new(Mutex::new(test_buffer));
+        let buffer = Arc::new(RwLock::new(test_buffer));
         let buffer_clone = buffer.clone();
-
-        let buffer_acc = Arc::new(Mutex::new(acc_vec));
-        let buffer_acc_clone = buffer_acc.clone();
-
-        let counter = Arc::new(Mutex::new(0));
-        let counter_clone = counter.clone();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
 
         tokio::task::spawn(async move {
-            match maintain_space_tree_buffer(&buffer, 10).await {
+            match maintain_space_tree_buffer(&buffer, 10, Some(sender)).await {
                 Ok(()) => (),
                 Err(e) => error!("Space tree buffer maintainer failed with: '{}'.", e),
             };
         });
 
+        // The gripper keeps being broadcasted while the camera goes down
+        let buffer_broadcaster = buffer_clone.clone();
         let _ = timeout(Duration::from_millis(500), async move {
+            let mut interval = interval(Duration::from_millis(50));
             loop {
                 interval.tick().await;
-                let counter_local = counter_clone.lock().unwrap().clone();
-                *counter_clone.clone().lock().unwrap() = counter_local + 1;
-                let mut buffer_acc_local = buffer_acc.lock().unwrap().clone();
-                let buffer_clone_local = buffer_clone.lock().unwrap().clone();
-                buffer_acc_local.push(buffer_clone_local);
-                *buffer_acc.lock().unwrap() = buffer_acc_local;
+                buffer_broadcaster
+                    .write()
+                    .unwrap()
+                    .insert(frame("gripper", Some(Duration::from_millis(100))));
             }
         })
         .await;
-        let mut buffer_acc_clone_local = buffer_acc_clone.lock().unwrap().clone();
-        buffer_acc_clone_local.drain(0..1);
-        let truth_vec = buffer_acc_clone_local
-            .windows(2)
-            .map(|x| {
-                let prev_test_transform = x[0].get("test_transform").unwrap();
-                let next_test_transform = x[1].get("test_transform").unwrap();
-                println!("{:?}", prev_test_transform.time_stamp);
-                next_test_transform.time_stamp > prev_test_transform.time_stamp
-            })
-            .collect::<Vec<bool>>();
-        assert!(truth_vec.iter().all(|f| *f));
+
+        let evicted = receiver.recv().await.unwrap();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].child_frame_id, "camera");
+        assert!(receiver.try_recv().is_err());
+
+        let buffer_local = buffer_clone.read().unwrap().clone();
+        assert!(buffer_local.contains_key("static"));
+        assert!(buffer_local.contains_key("gripper"));
+        assert!(!buffer_local.contains_key("camera"));
+
+        // Eviction goes on when the receiver is gone
+        drop(receiver);
+        buffer_clone
+            .write()
+            .unwrap()
+            .insert(frame("camera", Some(Duration::from_millis(100))));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!buffer_clone.read().unwrap().contains_key("camera"));
+    }
+
+    #[tokio::test]
+    async fn test_space_tree() {
+        let tree = SpaceTree::new(TreeConfig::default());
+        let t0 = Instant::now();
+        let frame = |parent: &str, child: &str, x: f64, time_stamp: Instant| TransformStamped {
+            time_stamp,
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        };
+        tree.insert(&vec![
+            frame("world", "table", 1.0, t0),
+            frame("table", "fixture", 2.0, t0),
+            frame("fixture", "part", 0.5, t0),
+        ])
+        .await
+        .unwrap();
+        tree.insert(&vec![frame("world", "table", 3.0, t0 + Duration::from_millis(100))])
+            .await
+            .unwrap();
+
+        assert_eq!(tree.lookup("world", "part").unwrap().transform.translation.x, 5.5);
+        let policy = LookupPolicy::default();
+        let past = tree.lookup_at("world", "part", t0, &policy).unwrap();
+        assert_eq!(past.transform.translation.x, 3.5);
+        assert_eq!(tree.children("table").len(), 1);
+        assert!(tree.validate().is_ok());
+
+        let snapshot = tree.snapshot();
+        let report = tree.remove("fixture", RemovalMode::AdoptChildren).unwrap();
+        assert_eq!(report.reparented.len(), 1);
+        assert_eq!(tree.children("table")[0].child_frame_id, "part");
+        assert!(snapshot.contains_key("fixture"));
+        assert!(!tree.contains("fixture"));
+        assert_eq!(tree.lookup("world", "part").unwrap().transform.translation.x, 5.5);
+        // The past is kept, the adopted child has the same sample in both buffers
+        assert!(tree.snapshot_at(t0).contains_key("fixture"));
+        assert!(!tree.snapshot_at(Instant::now()).contains_key("fixture"));
+        assert_eq!(tree.lookup_at("world", "part", t0, &policy).unwrap().transform.translation.x, 3.5);
+        assert_eq!(tree.snapshot_at(t0).get("part"), tree.snapshot().get("part"));
+
+        // A cycle is rejected and the tree stays valid
+        let report = tree
+            .insert(&vec![frame("part", "table", 0.0, t0 + Duration::from_millis(200))])
+            .await
+            .unwrap();
+        assert!(matches!(report.rejected[0].1, InsertError::CycleDetected(_)));
+        assert!(tree.validate().is_ok());
+        assert_eq!(tree.lookup("world", "part").unwrap().transform.translation.x, 5.5);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_full() {
+        let tree = SpaceTree::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(1000);
+        let at = |parent: &str, child: &str, x: f64, z: f64, time_stamp: Instant| TransformStamped {
+            time_stamp,
+            transform: Isometry3::translation(x, 0.0, z),
+            ..linked(parent, child)
+        };
+        tree.insert(&vec![
+            at("world", "conveyor", 0.0, 0.0, t0),
+            at("world", "camera", 0.0, 2.0, t0),
+            at("world", "gripper", 1.0, 1.0, t0),
+            at("camera", "detection", 0.0, -2.0, t0),
+        ])
+        .await
+        .unwrap();
+        tree.insert(&vec![at("world", "conveyor", 1.0, 0.0, t1)]).await.unwrap();
+
+        // The part detected at t0 rides along with the conveyor to below the gripper
+        let hold = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..LookupPolicy::default()
+        };
+        let result = tree.lookup_full("gripper", t1, "detection", t0, "conveyor", &hold).unwrap();
+        assert!((result.transform.translation.vector - Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let storage = Arc::new(MemoryStorage::default());
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        let t0 = Instant::now();
+        let frames = [linked("world", "rig"), linked("rig", "camera"), linked("camera", "lens")]
+            .map(|frame| TransformStamped { time_stamp: t0, ..frame });
+        tree.insert(&frames.to_vec()).await.unwrap();
+        let mut events = tree.subscribe();
+
+        // Renaming a frame to its own parent is refused
+        assert!(tree.rename("camera", "rig").is_err());
+
+        let renamed = tree.rename("camera", "camera_left").unwrap().frame;
+        assert_eq!(renamed.parent_frame_id, "rig");
+        assert!(!tree.contains("camera"));
+        assert!(tree.lookup("world", "lens").is_ok());
+        assert!(tree.lookup_at("world", "lens", t0, &LookupPolicy::default()).is_ok());
+        assert_eq!(tree.snapshot_at(t0).get("lens").unwrap().parent_frame_id, "camera_left");
+        assert!(storage.get("camera").unwrap().is_none());
+        assert_eq!(storage.get("lens").unwrap().unwrap().parent_frame_id, "camera_left");
+
+        assert_eq!(events.recv().await.unwrap(), FrameEvent::Removed(TransformStamped {
+            child_frame_id: "camera".to_string(),
+            ..renamed.clone()
+        }));
+        assert_eq!(events.recv().await.unwrap(), FrameEvent::Added(renamed));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            FrameEvent::Reparented { frame, previous_parent_frame_id }
+                if frame.child_frame_id == "lens" && previous_parent_frame_id == "camera"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_late_samples_only_go_to_the_history() {
+        let tree = SpaceTree::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(100);
+        let camera = |parent: &str, x: f64, time_stamp: Instant| TransformStamped {
+            time_stamp,
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            ..linked(parent, "camera")
+        };
+        tree.insert(&vec![linked("world", "rig"), camera("world", 2.0, t1)]).await.unwrap();
+        let mut events = tree.subscribe();
+
+        let report = tree
+            .insert(&vec![camera("world", 1.0, t0), camera("rig", 1.0, t0)])
+            .await
+            .unwrap();
+        assert!(report.accepted.is_empty());
+        assert_eq!(report.late.len(), 2);
+        assert!(timeout(Duration::from_millis(10), events.recv()).await.is_err());
+
+        let latest = tree.lookup("world", "camera").unwrap();
+        assert_eq!(latest.transform.translation.x, 2.0);
+        assert_eq!(tree.children("world").len(), 2);
+        let hold = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..LookupPolicy::default()
+        };
+        let now = tree.lookup_at("world", "camera", t1, &hold).unwrap();
+        assert_eq!(now.transform.translation.x, 2.0);
+        assert_eq!(tree.snapshot_at(t0).get("camera").unwrap().parent_frame_id, "rig");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintain_forgets_evicted_frames() {
+        let storage = Arc::new(MemoryStorage::default());
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        let camera = TransformStamped {
+            time_to_live: Some(Duration::from_millis(50)),
+            ..linked("world", "camera")
+        };
+        tree.insert(&vec![camera.clone()]).await.unwrap();
+        let mut events = tree.subscribe();
+        let tree_clone = tree.clone();
+        tokio::spawn(async move {
+            let _ = tree_clone.maintain(10).await;
+        });
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Expired(_))));
+
+        let hold = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..LookupPolicy::default()
+        };
+        assert!(matches!(
+            tree.lookup_at("world", "camera", Instant::now(), &hold),
+            Err(LookupError::TimeOutOfRange { range: OutOfRange::Removed, .. })
+        ));
+        assert!(!tree.snapshot_at(Instant::now()).contains_key("camera"));
+        assert!(storage.get("camera").unwrap().is_none());
+
+        // The past of the frame is kept until the history config prunes it
+        assert!(tree.lookup_at("world", "camera", camera.time_stamp, &hold).is_ok());
+        tokio::time::sleep(tree.config().history.max_duration + Duration::from_millis(1)).await;
+        tree.insert(&vec![TransformStamped {
+            time_stamp: Instant::now(),
+            ..linked("world", "light")
+        }])
+        .await
+        .unwrap();
+        assert!(tree.lookup_at("world", "camera", camera.time_stamp, &hold).is_ok());
+        tree.remove("light", RemovalMode::Cascade).unwrap();
+        assert!(tree.lookup_at("world", "camera", camera.time_stamp, &hold).is_err());
+    }
+
+    // Takes every frame except the broken one
+    #[derive(Debug, Default)]
+    struct FailingStorage(MemoryStorage);
+
+    impl FrameStorage for FailingStorage {
+        fn get(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+            self.0.get(frame_id)
+        }
+        fn put(&self, frame: &TransformStamped) -> Result<(), ErrorMsg> {
+            match frame.child_frame_id.as_str() {
+                "broken" => Err(ErrorMsg::new("Storage is down.")),
+                _ => self.0.put(frame),
+            }
+        }
+        fn remove(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+            self.0.remove(frame_id)
+        }
+        fn frames(&self) -> Result<Vec<TransformStamped>, ErrorMsg> {
+            self.0.frames()
+        }
+        fn history(&self, frame_id: &str) -> Result<Vec<TransformStamped>, ErrorMsg> {
+            self.0.history(frame_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_reports_unstored_frames() {
+        let storage = Arc::new(FailingStorage::default());
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        let mut events = tree.subscribe();
+        let report = tree
+            .insert(&vec![linked("world", "broken"), linked("world", "camera")])
+            .await
+            .unwrap();
+
+        assert_eq!(report.accepted.len(), 2);
+        assert_eq!(report.unstored.len(), 1);
+        assert_eq!(report.unstored[0].0.child_frame_id, "broken");
+        assert!(tree.contains("broken"));
+        assert!(storage.get("camera").unwrap().is_some());
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Added(f)) if f.child_frame_id == "broken"));
+        assert!(matches!(events.recv().await, Ok(FrameEvent::Added(f)) if f.child_frame_id == "camera"));
+    }
+
+    #[tokio::test]
+    async fn test_changes_report_unstored_frames() {
+        let storage = Arc::new(FailingStorage::default());
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        let t0 = Instant::now();
+        let frames = [linked("world", "rig"), linked("rig", "camera"), linked("camera", "lens")]
+            .map(|frame| TransformStamped { time_stamp: t0, ..frame });
+        tree.insert(&frames.to_vec()).await.unwrap();
+
+        // The new name can't be stored, everything else still is
+        let report = tree.rename("camera", "broken").unwrap();
+        assert!(!report.unstored.is_empty());
+        assert!(report.unstored.iter().all(|(frame, _)| frame.child_frame_id == "broken"));
+        assert!(storage.get("camera").unwrap().is_none());
+        assert_eq!(storage.get("lens").unwrap().unwrap().parent_frame_id, "broken");
+
+        let report = tree.remove("rig", RemovalMode::AdoptChildren).unwrap();
+        assert_eq!(report.unstored.len(), 1);
+        assert!(storage.get("rig").unwrap().is_none());
+        assert_eq!(tree.snapshot().get("broken").unwrap().parent_frame_id, "world");
+
+        let report = tree.reparent("lens", "world", true).unwrap();
+        assert!(report.unstored.is_empty());
+        assert_eq!(storage.get("lens").unwrap().unwrap().parent_frame_id, "world");
+    }
+
+    #[tokio::test]
+    async fn test_reparent() {
+        let storage = Arc::new(MemoryStorage::default());
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        let t0 = Instant::now() - Duration::from_secs(1);
+        let frames = [linked("world", "table"), linked("world", "gripper"), linked("table", "part")]
+            .map(|frame| TransformStamped { time_stamp: t0, ..frame });
+        tree.insert(&frames.to_vec()).await.unwrap();
+        let mut events = tree.subscribe();
+
+        let t1 = t0 + Duration::from_millis(100);
+        let hold = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..LookupPolicy::default()
+        };
+        tree.reparent_at("part", "gripper", true, t1, &hold).unwrap();
+        assert_eq!(tree.children("gripper")[0].child_frame_id, "part");
+        assert_eq!(tree.snapshot_at(t0).get("part").unwrap().parent_frame_id, "table");
+        assert_eq!(storage.get("part").unwrap().unwrap().parent_frame_id, "gripper");
+        assert!(matches!(
+            events.recv().await,
+            Ok(FrameEvent::Reparented { previous_parent_frame_id, .. }) if previous_parent_frame_id == "table"
+        ));
+
+        // A change in the past only goes to the history
+        let t_half = t0 + Duration::from_millis(50);
+        tree.reparent_at("part", "world", true, t_half, &hold).unwrap();
+        assert_eq!(tree.snapshot_at(t_half).get("part").unwrap().parent_frame_id, "world");
+        assert_eq!(tree.children("gripper")[0].child_frame_id, "part");
+
+        tree.reparent("part", "table", true).unwrap();
+        assert_eq!(tree.children("table")[0].child_frame_id, "part");
+        assert_eq!(tree.snapshot_at(Instant::now()).get("part").unwrap().parent_frame_id, "table");
+        assert_eq!(storage.get("part").unwrap().unwrap().parent_frame_id, "table");
+        assert!(matches!(
+            events.recv().await,
+            Ok(FrameEvent::Reparented { previous_parent_frame_id, .. }) if previous_parent_frame_id == "gripper"
+        ));
+        assert!(tree.reparent("part", "part", true).is_err());
+    }
+
+    fn linked(parent: &str, child: &str) -> TransformStamped {
+        TransformStamped {
+            parent_frame_id: parent.to_string(),
+            ..frame(child, None)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_transform() {
+        let tree = SpaceTree::default();
+        let t0 = Instant::now();
+        let tree_clone = tree.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tree_clone.insert(&vec![linked("world", "camera")]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tree_clone.insert(&vec![linked("camera", "marker")]).await.unwrap();
+        });
+
+        let result = tree.wait_for_transform("world", "marker", Duration::from_secs(1)).await;
+        assert!(result.is_ok());
+        assert!(Instant::now() - t0 < Duration::from_millis(300));
+
+        let result = tree.wait_for_transform("world", "gripper", Duration::from_millis(500)).await;
+        assert_eq!(result, Err(LookupError::UnknownSourceFrame("gripper".to_string())));
+        tree.insert(&vec![linked("robot", "gripper")]).await.unwrap();
+        let result = tree.wait_for_transform("world", "gripper", Duration::from_millis(500)).await;
+        assert!(matches!(result, Err(LookupError::DisconnectedFrames { .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_transform_at() {
+        let tree = SpaceTree::default();
+        let t0 = Instant::now();
+        tree.insert(&vec![TransformStamped { time_stamp: t0, ..linked("world", "camera") }])
+            .await
+            .unwrap();
+        let policy = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Reject,
+            ..LookupPolicy::default()
+        };
+        let requested = t0 + Duration::from_millis(150);
+
+        let tree_clone = tree.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            tree_clone.insert(&vec![linked("world", "camera")]).await.unwrap();
+        });
+
+        let result = tree
+            .wait_for_transform_at("world", "camera", requested, &policy, Duration::from_millis(100))
+            .await;
+        assert!(matches!(
+            result,
+            Err(LookupError::TimeOutOfRange { range: OutOfRange::Future, .. })
+        ));
+        let result = tree
+            .wait_for_transform_at("world", "camera", requested, &policy, Duration::from_secs(1))
+            .await;
+        assert_eq!(result.unwrap().time_stamp, requested);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_planned_transform() {
+        let tree = SpaceTree::default();
+        let t0 = Instant::now();
+        tree.insert(&vec![TransformStamped { time_stamp: t0, ..linked("world", "tcp") }])
+            .await
+            .unwrap();
+        let policy = LookupPolicy {
+            source: TimelineSource::Planned,
+            ..LookupPolicy::default()
+        };
+        let requested = t0 + Duration::from_secs(2);
+
+        let tree_clone = tree.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let plan = [1, 3].map(|secs| TransformStamped {
+                time_stamp: t0 + Duration::from_secs(secs),
+                ..linked("world", "tcp")
+            });
+            tree_clone.set_plans(&plan.to_vec()).await.unwrap();
+        });
+
+        let result = tree
+            .wait_for_transform_at("world", "tcp", requested, &policy, Duration::from_secs(1))
+            .await;
+        assert_eq!(result.unwrap().time_stamp, requested);
+        assert!(Instant::now() - t0 < Duration::from_millis(200));
     }
 }
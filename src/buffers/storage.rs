@@ -0,0 +1,182 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+// Where the frames of a space tree are kept outside of the process local buffers. A space tree
+// loads its frames from the storage when it is created and writes every change through to it,
+// so that several trees, possibly in different processes, can share the same frames.
+pub trait FrameStorage: Debug + Send + Sync {
+    // The latest sample of the frame
+    fn get(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg>;
+    // Store the frame as the latest sample and add it to its history
+    fn put(&self, frame: &TransformStamped) -> Result<(), ErrorMsg>;
+    // Remove the frame together with its history, returns the latest sample
+    fn remove(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg>;
+    // The latest samples of all frames
+    fn frames(&self) -> Result<Vec<TransformStamped>, ErrorMsg>;
+    // All kept samples of the frame, oldest first
+    fn history(&self, frame_id: &str) -> Result<Vec<TransformStamped>, ErrorMsg>;
+}
+
+// Frames kept in memory, can be shared between trees in the same process and used as a test double
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    history: RwLock<HashMap<String, TransformHistory>>,
+    config: HistoryConfig,
+}
+
+impl MemoryStorage {
+    pub fn new(config: HistoryConfig) -> MemoryStorage {
+        MemoryStorage {
+            history: RwLock::new(HashMap::new()),
+            config,
+        }
+    }
+}
+
+impl FrameStorage for MemoryStorage {
+    fn get(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+        Ok(self
+            .history
+            .read()
+            .unwrap()
+            .get(frame_id)
+            .and_then(|history| history.latest().cloned()))
+    }
+
+    fn put(&self, frame: &TransformStamped) -> Result<(), ErrorMsg> {
+        self.history
+            .write()
+            .unwrap()
+            .entry(frame.child_frame_id.clone())
+            .or_insert_with(|| TransformHistory::new(self.config))
+            .insert(frame.clone());
+        Ok(())
+    }
+
+    fn remove(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+        Ok(self
+            .history
+            .write()
+            .unwrap()
+            .remove(frame_id)
+            .and_then(|history| history.latest().cloned()))
+    }
+
+    fn frames(&self) -> Result<Vec<TransformStamped>, ErrorMsg> {
+        Ok(latest_frames(&self.history.read().unwrap()).into_frames().into_values().collect())
+    }
+
+    fn history(&self, frame_id: &str) -> Result<Vec<TransformStamped>, ErrorMsg> {
+        Ok(self
+            .history
+            .read()
+            .unwrap()
+            .get(frame_id)
+            .map(|history| history.samples().iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str, x: f64, time_stamp: Instant) -> TransformStamped {
+        TransformStamped {
+            time_stamp,
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::default();
+        let t0 = Instant::now();
+        storage.put(&frame("world", "table", 1.0, t0)).unwrap();
+        storage.put(&frame("world", "table", 2.0, t0 + Duration::from_millis(10))).unwrap();
+        storage.put(&frame("table", "part", 0.5, t0)).unwrap();
+
+        assert_eq!(storage.get("table").unwrap().unwrap().transform.translation.x, 2.0);
+        assert_eq!(storage.history("table").unwrap().len(), 2);
+        assert_eq!(storage.frames().unwrap().len(), 2);
+
+        assert!(storage.remove("table").unwrap().is_some());
+        assert!(storage.get("table").unwrap().is_none());
+        assert!(storage.history("table").unwrap().is_empty());
+        assert!(storage.remove("table").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_trees_share_storage() {
+        let storage: Arc<dyn FrameStorage> = Arc::new(MemoryStorage::default());
+        let t0 = Instant::now();
+
+        let first = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        first
+            .insert(&vec![frame("world", "table", 1.0, t0), frame("table", "part", 0.5, t0)])
+            .await
+            .unwrap();
+
+        let second = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        assert_eq!(second.lookup("world", "part").unwrap().transform.translation.x, 1.5);
+        let past = second.lookup_at("world", "part", t0, &LookupPolicy::default()).unwrap();
+        assert_eq!(past.transform.translation.x, 1.5);
+
+        second.remove("part", RemovalMode::Cascade).unwrap();
+        assert!(storage.get("part").unwrap().is_none());
+        assert_eq!(storage.frames().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stored_frames_are_checked() {
+        let storage: Arc<dyn FrameStorage> = Arc::new(MemoryStorage::default());
+        let t0 = Instant::now();
+        storage.put(&frame("world", "table", 1.0, t0)).unwrap();
+        storage.put(&frame("b", "a", 0.0, t0)).unwrap();
+        storage.put(&frame("a", "b", 0.0, t0)).unwrap();
+
+        // Only one of the frames of the cycle is loaded
+        let tree = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        assert!(tree.validate().is_ok());
+        assert!(tree.contains("table"));
+        assert_eq!(tree.snapshot().len(), 2);
+
+        // Other trees can't move static frames or close cycles either
+        let fixture = TransformStamped {
+            frame_kind: FrameKind::Static,
+            ..frame("table", "fixture", 0.5, t0)
+        };
+        tree.load_scenario(&vec![fixture]).await.unwrap();
+        tree.apply_stored_frame(frame("world", "fixture", 2.0, t0));
+        assert_eq!(tree.lookup("world", "fixture").unwrap().transform.translation.x, 1.5);
+        tree.apply_stored_frame(frame("fixture", "table", 0.0, t0));
+        assert!(tree.validate().is_ok());
+        assert_eq!(tree.snapshot().get("table").unwrap().parent_frame_id, "world");
+
+        // But the scenarios they load reach this tree
+        let t1 = t0 + Duration::from_millis(100);
+        tree.apply_stored_frame(TransformStamped {
+            frame_kind: FrameKind::Static,
+            ..frame("world", "fixture", 2.0, t1)
+        });
+        tree.apply_stored_frame(TransformStamped {
+            frame_kind: FrameKind::Static,
+            ..frame("world", "shelf", 3.0, t1)
+        });
+        assert_eq!(tree.lookup("world", "fixture").unwrap().transform.translation.x, 2.0);
+        assert_eq!(tree.snapshot().get("shelf").unwrap().frame_kind, FrameKind::Static);
+        assert_eq!(tree.snapshot_at(t1).get("fixture").unwrap().transform.translation.x, 2.0);
+    }
+}
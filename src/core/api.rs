@@ -1,25 +1,563 @@
 use log::{info, warn, error};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 
 use crate::*;
 
+// Every frame is validated against the buffer as it is after the frames before it in the batch,
+// the report says which frames were accepted and why the others were rejected.
+// These are the frames of the broadcasters, they can't add or change static frames.
 pub async fn add_frames(
-    buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
+    buffer: &Arc<RwLock<FrameTree>>,
     frames: &Vec<TransformStamped>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer_local = buffer.lock().unwrap().clone();
-    // let mut updated_buffer = HashMap::new();
+    config: &TreeConfig,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    insert_frames(buffer, frames, config, false)
+}
+
+// Same as add_frames for the frames of a scenario or of a reloaded tree,
+// the only way to add or change static frames
+pub async fn add_scenario_frames(
+    buffer: &Arc<RwLock<FrameTree>>,
+    frames: &Vec<TransformStamped>,
+    config: &TreeConfig,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    insert_frames(buffer, frames, config, true)
+}
+
+pub(crate) fn insert_frames(
+    buffer: &Arc<RwLock<FrameTree>>,
+    frames: &Vec<TransformStamped>,
+    config: &TreeConfig,
+    scenario: bool,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    let mut report = InsertReport::default();
+    // Only the changed frames are written, the lock is held for the whole batch
+    // so that readers never see a partially applied update
+    let mut buffer_local = buffer.write().unwrap();
     for frame in frames {
-        if frame.child_frame_id == "world" {
-            error!("Frame name 'world' is reserved.")
+        let frame = with_default_time_to_live(frame, config);
+        match check_insert(&frame, &buffer_local, config, scenario) {
+            // Samples can arrive out of order, an older one must not replace the latest frame
+            Ok(()) if buffer_local
+                .get(&frame.child_frame_id)
+                .is_some_and(|latest| frame.time_stamp < latest.time_stamp) =>
+            {
+                info!("Frame '{}' is older than the latest one, only kept in the history", frame.child_frame_id);
+                report.late.push(frame);
+            }
+            Ok(()) => {
+                let previous = buffer_local.insert(frame.clone());
+                match &previous {
+                    Some(_) => warn!("Frame '{}' already exists, now updated", frame.child_frame_id),
+                    None => info!("Frame '{}' added as child of frame '{}'", frame.child_frame_id, frame.parent_frame_id)
+                }
+                report.accepted.push(change_event(previous.as_ref(), frame));
+            }
+            Err(reason) => {
+                error!("{}", reason);
+                report.rejected.push((frame, reason));
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn check_insert(
+    frame: &TransformStamped,
+    buffer: &FrameTree,
+    config: &TreeConfig,
+    scenario: bool,
+) -> Result<(), InsertError> {
+    check_frame(frame, &config.names)?;
+    if !scenario && changes_static_frame(buffer.get(&frame.child_frame_id), frame) {
+        Err(InsertError::StaticFrame(frame.child_frame_id.clone()))
+    } else if !buffer.contains_key(&frame.child_frame_id) && buffer.len() >= config.max_frames {
+        Err(InsertError::TooManyFrames {
+            frame: frame.child_frame_id.clone(),
+            limit: config.max_frames,
+        })
+    } else {
+        check_placement(frame, buffer, config)
+    }
+}
+
+// The parent of the frame must not be below it, and the chain through it, together with
+// the frames below it, must stay within the limit
+pub(crate) fn check_placement(frame: &TransformStamped, buffer: &FrameTree, config: &TreeConfig) -> Result<(), InsertError> {
+    if let Some(cycle) = cycle_produced_by(frame, buffer) {
+        Err(InsertError::CycleDetected(cycle))
+    } else if chain_length(&frame.parent_frame_id, buffer) + 1 + height_if_moved(frame, buffer)
+        > config.max_chain_length
+    {
+        Err(InsertError::ChainTooLong {
+            frame: frame.child_frame_id.clone(),
+            limit: config.max_chain_length,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// The frames below a frame move with it, only needed when it gets a new parent
+fn height_if_moved(frame: &TransformStamped, buffer: &FrameTree) -> u64 {
+    match buffer.parent(&frame.child_frame_id) {
+        Some(parent) if parent == frame.parent_frame_id => 0,
+        _ => subtree_height(&frame.child_frame_id, buffer),
+    }
+}
+
+fn with_default_time_to_live(frame: &TransformStamped, config: &TreeConfig) -> TransformStamped {
+    match (frame.frame_kind, frame.time_to_live) {
+        (FrameKind::Dynamic, None) => TransformStamped {
+            time_to_live: config.default_time_to_live,
+            ..frame.clone()
+        },
+        _ => frame.clone(),
+    }
+}
+
+// Checks that don't depend on the other frames, the scenario loader applies them too
+pub fn check_frame(frame: &TransformStamped, names: &ReservedNames) -> Result<(), InsertError> {
+    let rotation = frame.transform.rotation.coords;
+    if names.is_reserved(&frame.child_frame_id) {
+        Err(InsertError::ReservedName(frame.child_frame_id.clone()))
+    } else if frame.child_frame_id == frame.parent_frame_id {
+        Err(InsertError::SelfParent(frame.child_frame_id.clone()))
+    } else if !frame.transform.translation.vector.iter().all(|x| x.is_finite()) {
+        Err(InsertError::NonFiniteTranslation(frame.child_frame_id.clone()))
+    } else if !rotation.iter().all(|x| x.is_finite()) || (rotation.norm() - 1.0).abs() > UNIT_TOLERANCE {
+        Err(InsertError::InvalidRotation(frame.child_frame_id.clone()))
+    } else {
+        Ok(())
+    }
+}
+
+// How far the norm of a rotation quaternion may be off after rounding errors
+const UNIT_TOLERANCE: f64 = 1e-6;
+
+// The change the frame makes to the latest frames, given the frame it replaced
+pub(crate) fn change_event(previous: Option<&TransformStamped>, frame: TransformStamped) -> FrameEvent {
+    match previous {
+        None => FrameEvent::Added(frame),
+        Some(previous) if previous.parent_frame_id != frame.parent_frame_id => FrameEvent::Reparented {
+            frame,
+            previous_parent_frame_id: previous.parent_frame_id.clone(),
+        },
+        Some(_) => FrameEvent::Updated(frame),
+    }
+}
+
+// Static frames come from the scenario, a broadcaster labelling its frame as static
+// should not be able to add or move them either
+fn changes_static_frame(existing: Option<&TransformStamped>, frame: &TransformStamped) -> bool {
+    frame.frame_kind == FrameKind::Static
+        || existing.is_some_and(|existing| existing.frame_kind == FrameKind::Static)
+}
+
+// Same as add_frames, but every frame keeps a bounded history of its samples. The frames are
+// checked against the latest sample of every frame, which are collected from all histories
+// for every batch. A SpaceTree checks against its latest frames instead.
+pub async fn add_frames_to_history(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    frames: &Vec<TransformStamped>,
+    config: &TreeConfig,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    insert_frames_to_history(buffer, frames, config, false)
+}
+
+// Same as add_scenario_frames, but every frame keeps a bounded history of its samples
+pub async fn add_scenario_frames_to_history(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    frames: &Vec<TransformStamped>,
+    config: &TreeConfig,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    insert_frames_to_history(buffer, frames, config, true)
+}
+
+fn insert_frames_to_history(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    frames: &Vec<TransformStamped>,
+    config: &TreeConfig,
+    scenario: bool,
+) -> Result<InsertReport, Box<dyn std::error::Error>> {
+    let mut report = InsertReport::default();
+    let mut buffer_local = buffer.write().unwrap();
+    let mut latest = latest_frames(&buffer_local);
+    for frame in frames {
+        let frame = with_default_time_to_live(frame, config);
+        if let Err(reason) = check_insert(&frame, &latest, config, scenario) {
+            error!("{}", reason);
+            report.rejected.push((frame, reason));
+            continue;
+        }
+        // A sample older than the latest one doesn't change the latest frames
+        if record_sample(&mut buffer_local, frame.clone(), config) {
+            let previous = latest.insert(frame.clone());
+            report.accepted.push(change_event(previous.as_ref(), frame));
         } else {
-            match buffer_local.insert(frame.child_frame_id.clone(), frame.clone()) {
-                Some(_) => warn!("Frame '{}' already exists, now updated", frame.child_frame_id),
-                None => info!("Frame '{}' added as child of frame '{}'", frame.child_frame_id, frame.parent_frame_id)
+            report.late.push(frame);
+        }
+    }
+    Ok(report)
+}
+
+// Add samples that were already checked against the latest frames to their histories
+pub(crate) fn record_samples(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    frames: &[TransformStamped],
+    config: &TreeConfig,
+) {
+    let mut buffer_local = buffer.write().unwrap();
+    for frame in frames {
+        record_sample(&mut buffer_local, frame.clone(), config);
+    }
+}
+
+// Returns whether the sample is the newest of its frame
+fn record_sample(
+    buffer: &mut HashMap<String, TransformHistory>,
+    frame: TransformStamped,
+    config: &TreeConfig,
+) -> bool {
+    let history = match buffer.get_mut(&frame.child_frame_id) {
+        Some(history) => {
+            // The config can change between calls, the new bounds apply to the old samples too
+            history.config = config.history;
+            if let Some(parent) = history.parent_at(frame.time_stamp) {
+                if parent != frame.parent_frame_id {
+                    info!("Frame '{}' moved from frame '{}' to frame '{}'", frame.child_frame_id, parent, frame.parent_frame_id)
+                }
+            }
+            history
+        }
+        None => {
+            info!("Frame '{}' added as child of frame '{}'", frame.child_frame_id, frame.parent_frame_id);
+            buffer
+                .entry(frame.child_frame_id.clone())
+                .or_insert_with(|| TransformHistory::new(config.history))
+        }
+    };
+    history.insert(frame.clone());
+    history.latest() == Some(&frame)
+}
+
+// Replace the planned timelines of the frames that appear in the plan, samples of
+// the same frame form its timeline. Frames don't have to be observed to be planned.
+// Every sample is checked like an inserted frame against the latest frames, which
+// are collected from all histories. A SpaceTree checks against its latest frames instead.
+pub async fn set_frame_plans(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    plan: &Vec<TransformStamped>,
+    config: &TreeConfig,
+) -> Result<PlanReport, Box<dyn std::error::Error>> {
+    let latest = latest_frames(&buffer.read().unwrap());
+    Ok(plan_frames(buffer, plan, &latest, config))
+}
+
+pub(crate) fn plan_frames(
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    plan: &Vec<TransformStamped>,
+    latest: &FrameTree,
+    config: &TreeConfig,
+) -> PlanReport {
+    let mut timelines = HashMap::<String, Vec<TransformStamped>>::new();
+    for frame in plan {
+        timelines
+            .entry(frame.child_frame_id.clone())
+            .or_default()
+            .push(frame.clone());
+    }
+
+    let mut report = PlanReport::default();
+    let mut buffer_local = buffer.write().unwrap();
+    for (frame_id, timeline) in timelines {
+        match timeline.iter().find_map(|sample| check_plan(sample, latest, config).err()) {
+            Some((sample, reason)) => {
+                error!("Plan of frame '{}' is rejected: {}", frame_id, reason);
+                report.rejected.push((sample.clone(), reason));
             }
+            None => {
+                info!("Frame '{}' planned with {} samples", frame_id, timeline.len());
+                buffer_local
+                    .entry(frame_id.clone())
+                    .or_insert_with(|| TransformHistory::new(config.history))
+                    .set_plan(timeline);
+                report.planned.push(frame_id);
+            }
+        }
+    }
+    report
+}
+
+// Planned samples are checked like the frames of the broadcasters, only their own kind doesn't matter
+fn check_plan<'a>(
+    sample: &'a TransformStamped,
+    latest: &FrameTree,
+    config: &TreeConfig,
+) -> Result<(), (&'a TransformStamped, InsertError)> {
+    let is_static = latest
+        .get(&sample.child_frame_id)
+        .is_some_and(|frame| frame.frame_kind == FrameKind::Static);
+    check_frame(sample, &config.names)
+        .and_then(|()| match is_static {
+            true => Err(InsertError::StaticFrame(sample.child_frame_id.clone())),
+            false => check_placement(sample, latest, config),
+        })
+        .map_err(|reason| (sample, reason))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::{Isometry3, Quaternion, UnitQuaternion};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    fn table_frame(x: f64, frame_kind: FrameKind) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "world".to_string(),
+            child_frame_id: "table".to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind,
+        }
+    }
+
+    fn linked(parent: &str, child: &str) -> TransformStamped {
+        TransformStamped {
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            ..table_frame(1.0, FrameKind::Dynamic)
         }
     }
-    *buffer.lock().unwrap() = buffer_local;
-    Ok(())
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_static_frames_are_protected() {
+        let buffer = Arc::new(RwLock::new(FrameTree::new()));
+        let config = TreeConfig::default();
+        add_scenario_frames(&buffer, &vec![table_frame(1.0, FrameKind::Static)], &config).await.unwrap();
+
+        // Broadcasters can't move a static frame, not even by labelling their frame as static
+        let report = add_frames(
+            &buffer,
+            &vec![table_frame(2.0, FrameKind::Dynamic), table_frame(3.0, FrameKind::Static)],
+            &config,
+        )
+        .await
+        .unwrap();
+        assert!(report.accepted.is_empty());
+        assert_eq!(report.rejected[1].1, InsertError::StaticFrame("table".to_string()));
+        assert_eq!(buffer.read().unwrap().get("table").unwrap().transform.translation.x, 1.0);
+
+        // Nor add new static frames
+        let fixture = TransformStamped {
+            child_frame_id: "fixture".to_string(),
+            ..table_frame(1.0, FrameKind::Static)
+        };
+        let report = add_frames(&buffer, &vec![fixture], &config).await.unwrap();
+        assert_eq!(report.rejected[0].1, InsertError::StaticFrame("fixture".to_string()));
+
+        add_scenario_frames(&buffer, &vec![table_frame(3.0, FrameKind::Static)], &config).await.unwrap();
+        assert_eq!(buffer.read().unwrap().get("table").unwrap().transform.translation.x, 3.0);
+
+        let history_buffer = Arc::new(RwLock::new(HashMap::new()));
+        add_scenario_frames_to_history(&history_buffer, &vec![table_frame(1.0, FrameKind::Static)], &config)
+            .await
+            .unwrap();
+        let report = add_frames_to_history(
+            &history_buffer,
+            &vec![table_frame(2.0, FrameKind::Dynamic), table_frame(3.0, FrameKind::Static)],
+            &config,
+        )
+        .await
+        .unwrap();
+        assert!(report.accepted.is_empty());
+        assert_eq!(report.rejected[1].1, InsertError::StaticFrame("table".to_string()));
+        {
+            let history_buffer_local = history_buffer.read().unwrap();
+            let history = history_buffer_local.get("table").unwrap();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history.latest().unwrap().transform.translation.x, 1.0);
+        }
+
+        // The histories are checked against their latest samples like the latest frames are
+        let report = add_frames_to_history(
+            &history_buffer,
+            &vec![linked("table", "cup"), linked("cup", "plate"), linked("plate", "cup")],
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.accepted.len(), 2);
+        assert!(matches!(report.rejected[0].1, InsertError::CycleDetected(_)));
+        assert_eq!(history_buffer.read().unwrap().get("cup").unwrap().parent_at(Instant::now()), Some("table"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frames_are_rejected() {
+        let buffer = Arc::new(RwLock::new(FrameTree::new()));
+        let mut not_finite = linked("table", "camera");
+        not_finite.transform.translation.x = f64::INFINITY;
+        let mut not_unit = linked("table", "lens");
+        not_unit.transform.rotation = UnitQuaternion::new_unchecked(Quaternion::new(0.0, 0.0, 0.0, 0.0));
+
+        let report = add_frames(
+            &buffer,
+            &vec![
+                linked("world", "table"),
+                linked("table", "part"),
+                linked("part", "table"),
+                linked("part", "part"),
+                linked("part", "world"),
+                not_finite,
+                not_unit,
+            ],
+            &TreeConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.accepted.len(), 2);
+        let reasons = report.rejected.into_iter().map(|(_, reason)| reason).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                InsertError::CycleDetected(vec!["table".to_string(), "part".to_string()]),
+                InsertError::SelfParent("part".to_string()),
+                InsertError::ReservedName("world".to_string()),
+                InsertError::NonFiniteTranslation("camera".to_string()),
+                InsertError::InvalidRotation("lens".to_string()),
+            ]
+        );
+        assert!(lookup_transform_lca("world", "part", &buffer, &TreeConfig::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_custom_root_name() {
+        let buffer = Arc::new(RwLock::new(FrameTree::new()));
+        let mut config = TreeConfig {
+            names: ReservedNames::new("map"),
+            ..TreeConfig::default()
+        };
+        config.names.others.insert("odom".to_string());
+        let report = add_frames(
+            &buffer,
+            &vec![linked("map", "world"), linked("world", "map"), linked("map", "odom")],
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.accepted.len(), 1);
+        assert_eq!(report.rejected[0].1, InsertError::ReservedName("map".to_string()));
+        assert_eq!(report.rejected[1].1, InsertError::ReservedName("odom".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_tree_limits() {
+        let buffer = Arc::new(RwLock::new(FrameTree::new()));
+        let config = TreeConfig {
+            max_chain_length: 2,
+            max_frames: 3,
+            default_time_to_live: Some(Duration::from_secs(1)),
+            ..TreeConfig::default()
+        };
+        let report = add_frames(
+            &buffer,
+            &vec![
+                linked("world", "table"),
+                linked("table", "part"),
+                linked("part", "screw"),
+                linked("world", "robot"),
+                linked("world", "camera"),
+                linked("robot", "table"),
+            ],
+            &config,
+        )
+        .await
+        .unwrap();
+        let reasons = report.rejected.iter().map(|(_, reason)| reason.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                InsertError::ChainTooLong { frame: "screw".to_string(), limit: 2 },
+                InsertError::TooManyFrames { frame: "camera".to_string(), limit: 3 },
+                InsertError::ChainTooLong { frame: "table".to_string(), limit: 2 },
+            ]
+        );
+        assert_eq!(buffer.read().unwrap().get("part").unwrap().time_to_live, Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_history_config_is_applied() {
+        let buffer = Arc::new(RwLock::new(HashMap::new()));
+        let now = Instant::now();
+        let frames = (0..5)
+            .map(|i| TransformStamped {
+                time_stamp: now + Duration::from_millis(i),
+                ..table_frame(i as f64, FrameKind::Dynamic)
+            })
+            .collect::<Vec<TransformStamped>>();
+        add_frames_to_history(&buffer, &frames[..4].to_vec(), &TreeConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(buffer.read().unwrap().get("table").unwrap().len(), 4);
+
+        let config = TreeConfig {
+            history: HistoryConfig {
+                max_samples: 2,
+                ..HistoryConfig::default()
+            },
+            ..TreeConfig::default()
+        };
+        add_frames_to_history(&buffer, &frames[4..].to_vec(), &config).await.unwrap();
+        let buffer_local = buffer.read().unwrap();
+        assert_eq!(buffer_local.get("table").unwrap().len(), 2);
+        assert_eq!(buffer_local.get("table").unwrap().config, config.history);
+    }
+
+    #[tokio::test]
+    async fn test_set_frame_plans() {
+        let buffer = Arc::new(RwLock::new(HashMap::new()));
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        add_scenario_frames_to_history(&buffer, &vec![table_frame(1.0, FrameKind::Static)], &config)
+            .await
+            .unwrap();
+
+        let plan = (1..=3)
+            .map(|i| TransformStamped {
+                time_stamp: now + tokio::time::Duration::from_secs(i),
+                child_frame_id: "tcp".to_string(),
+                ..table_frame(i as f64, FrameKind::Dynamic)
+            })
+            .chain([table_frame(5.0, FrameKind::Dynamic)])
+            .collect::<Vec<TransformStamped>>();
+        let report = set_frame_plans(&buffer, &plan, &config).await.unwrap();
+        assert_eq!(report.planned, vec!["tcp"]);
+        assert_eq!(report.rejected[0].1, InsertError::StaticFrame("table".to_string()));
+
+        // Planned samples are checked like inserted frames, one bad sample rejects the timeline
+        let mut self_parent = linked("gripper", "gripper");
+        self_parent.transform.translation.x = f64::NAN;
+        let report = set_frame_plans(&buffer, &vec![self_parent, linked("tcp", "table")], &config)
+            .await
+            .unwrap();
+        assert!(report.planned.is_empty());
+        assert_eq!(report.rejected.len(), 2);
+        let report = set_frame_plans(&buffer, &vec![plan[0].clone(), table_frame(1.0, FrameKind::Dynamic)], &config)
+            .await
+            .unwrap();
+        assert_eq!(report.planned, vec!["tcp"]);
+
+        let buffer_local = buffer.read().unwrap();
+        assert_eq!(buffer_local.get("tcp").unwrap().planned().len(), 1);
+        assert!(buffer_local.get("tcp").unwrap().is_empty());
+        assert!(!buffer_local.contains_key("gripper"));
+        assert!(buffer_local.get("table").unwrap().planned().is_empty());
+    }
+}
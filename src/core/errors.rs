@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use tokio::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct ErrorMsg {
@@ -24,4 +25,176 @@ impl Error for ErrorMsg {
     fn description(&self) -> &str {
         &self.info
     }
-}
\ No newline at end of file
+}
+
+// In which way the requested time is not covered by the samples of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    // Older than the oldest sample
+    Past,
+    // Newer than the newest sample, and the policy doesn't allow holding or extrapolating
+    Future,
+    // Newer than the newest sample by more than the extrapolation horizon
+    BeyondHorizon(Duration),
+    // No sample at exactly the requested time
+    NotExact,
+    // The frame has no samples at all
+    NoSamples,
+    // The frame was removed from the tree before the requested time
+    Removed,
+}
+
+// Why a transform could not be resolved
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupError {
+    // The frame whose pose is looked up doesn't exist
+    UnknownSourceFrame(String),
+    // The frame in which the pose is expressed doesn't exist
+    UnknownTargetFrame(String),
+    // Both frames exist, but there is no chain of transforms between them
+    DisconnectedFrames { source: String, target: String },
+    // The chain runs into a cycle, the members are in parent order
+    CycleDetected(Vec<String>),
+    // The chain is longer than the limit
+    ChainTooLong { frame: String, limit: u64 },
+    // A frame of the chain has no data for the requested time
+    TimeOutOfRange {
+        frame: String,
+        gap: Duration,
+        range: OutOfRange,
+    },
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::UnknownSourceFrame(frame) => {
+                write!(f, "Source frame '{}' does not exist in the buffer.", frame)
+            }
+            LookupError::UnknownTargetFrame(frame) => {
+                write!(f, "Target frame '{}' does not exist in the buffer.", frame)
+            }
+            LookupError::DisconnectedFrames { source, target } => write!(
+                f,
+                "Frames '{}' and '{}' are not connected by a chain of transforms.",
+                source, target
+            ),
+            LookupError::CycleDetected(frames) => {
+                write!(f, "Frames {:?} form a cycle.", frames)
+            }
+            LookupError::ChainTooLong { frame, limit } => write!(
+                f,
+                "Chain of transforms from frame '{}' is longer than {} frames.",
+                frame, limit
+            ),
+            LookupError::TimeOutOfRange { frame, gap, range } => match range {
+                OutOfRange::Past => write!(
+                    f,
+                    "Lookup would require extrapolation {:?} into the past for frame '{}'.",
+                    gap, frame
+                ),
+                OutOfRange::Future => write!(
+                    f,
+                    "Lookup would require extrapolation {:?} into the future for frame '{}'.",
+                    gap, frame
+                ),
+                OutOfRange::BeyondHorizon(horizon) => write!(
+                    f,
+                    concat!(
+                        "Lookup would require extrapolation {:?} into the future for frame '{}', ",
+                        "which is beyond the extrapolation horizon of {:?}."
+                    ),
+                    gap, frame, horizon
+                ),
+                OutOfRange::NotExact => write!(
+                    f,
+                    concat!(
+                        "Frame '{}' has no sample at exactly the requested time, ",
+                        "the closest earlier one is {:?} older."
+                    ),
+                    frame, gap
+                ),
+                OutOfRange::NoSamples => write!(f, "Frame '{}' has no samples.", frame),
+                OutOfRange::Removed => write!(
+                    f,
+                    "Frame '{}' was removed {:?} before the requested time.",
+                    frame, gap
+                ),
+            },
+        }
+    }
+}
+
+impl Error for LookupError {}
+
+// Why a frame was not inserted into the tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertError {
+    // The name is reserved for the root of the tree
+    ReservedName(String),
+    // Static frames can only be updated by static frames
+    StaticFrame(String),
+    SelfParent(String),
+    // The new parent is below the frame, the members are in parent order starting with the frame
+    CycleDetected(Vec<String>),
+    // The chain from the frame up to its root would be longer than the limit
+    ChainTooLong { frame: String, limit: u64 },
+    // The tree already has as many frames as it may have
+    TooManyFrames { frame: String, limit: usize },
+    NonFiniteTranslation(String),
+    // The rotation is not a unit quaternion or contains NaNs
+    InvalidRotation(String),
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InsertError::ReservedName(frame) => write!(f, "Frame name '{}' is reserved.", frame),
+            InsertError::StaticFrame(frame) => write!(
+                f,
+                "Frame '{}' is static and can only be changed by loading a scenario.",
+                frame
+            ),
+            InsertError::SelfParent(frame) => write!(f, "Frame '{}' can't be its own parent.", frame),
+            InsertError::CycleDetected(frames) => {
+                write!(f, "Inserting frame '{}' would produce the cycle {:?}.", frames[0], frames)
+            }
+            InsertError::ChainTooLong { frame, limit } => write!(
+                f,
+                "Chain of transforms from frame '{}' would be longer than {} frames.",
+                frame, limit
+            ),
+            InsertError::TooManyFrames { frame, limit } => write!(
+                f,
+                "Frame '{}' can't be added, the tree already has {} frames.",
+                frame, limit
+            ),
+            InsertError::NonFiniteTranslation(frame) => {
+                write!(f, "Translation of frame '{}' is not finite.", frame)
+            }
+            InsertError::InvalidRotation(frame) => {
+                write!(f, "Rotation of frame '{}' is not a unit quaternion.", frame)
+            }
+        }
+    }
+}
+
+impl Error for InsertError {}
+
+impl From<InsertError> for ErrorMsg {
+    fn from(error: InsertError) -> ErrorMsg {
+        ErrorMsg::new(&error.to_string())
+    }
+}
+
+impl From<LookupError> for ErrorMsg {
+    fn from(error: LookupError) -> ErrorMsg {
+        ErrorMsg::new(&error.to_string())
+    }
+}
+
+impl From<redis::RedisError> for ErrorMsg {
+    fn from(error: redis::RedisError) -> ErrorMsg {
+        ErrorMsg::new(&format!("Redis failed with: '{}'.", error))
+    }
+}
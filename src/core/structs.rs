@@ -1,16 +1,19 @@
 use nalgebra::{Isometry3, Quaternion, UnitQuaternion, Vector3};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use structopt::StructOpt;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+use crate::InsertError;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct JsonTranslation {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct JsonRotation {
     pub x: f64,
     pub y: f64,
@@ -18,7 +21,7 @@ pub struct JsonRotation {
     pub w: f64,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct JsonTransform {
     pub translation: JsonTranslation,
     pub rotation: JsonRotation,
@@ -36,6 +39,36 @@ pub fn json_transform_to_isometry(json: JsonTransform) -> Isometry3<f64> {
     Isometry3::from_parts(translation.into(), rotation)
 }
 
+pub fn isometry_to_json_transform(isometry: &Isometry3<f64>) -> JsonTransform {
+    let translation = isometry.translation.vector;
+    let rotation = isometry.rotation.quaternion();
+    JsonTransform {
+        translation: JsonTranslation {
+            x: translation.x,
+            y: translation.y,
+            z: translation.z,
+        },
+        rotation: JsonRotation {
+            x: rotation.i,
+            y: rotation.j,
+            z: rotation.k,
+            w: rotation.w,
+        },
+    }
+}
+
+// Static frames are part of the scenario and can't be overwritten by broadcasters, dynamic frames
+// are broadcasted and expire when not updated in time, virtual frames are computed from other frames.
+// A frame that doesn't say what it is, like a scenario frame without a kind, is static.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameKind {
+    #[default]
+    Static,
+    Dynamic,
+    Virtual,
+}
+
 // Isometry3 should be similar to the Transform message in ROS
 #[derive(Debug, Clone, PartialEq)]
 pub struct TransformStamped {
@@ -46,6 +79,210 @@ pub struct TransformStamped {
     // #[serde(deserialize_with = "deserialize_isometry_3_f64")]
     pub transform: Isometry3<f64>,
     pub json_metadata: String,
+    // How long the frame stays valid after its last update, frames without it never expire
+    pub time_to_live: Option<Duration>,
+    pub frame_kind: FrameKind,
+}
+
+// How much past data is kept per frame. Samples older than `max_duration`
+// relative to the newest sample, or beyond `max_samples`, are dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryConfig {
+    pub max_duration: Duration,
+    pub max_samples: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_duration: Duration::from_secs(10),
+            max_samples: 1000,
+        }
+    }
+}
+
+// The name of the root frame and other names that broadcasters and scenarios can't use
+// for their frames, frames can only be attached below them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReservedNames {
+    pub root: String,
+    pub others: HashSet<String>,
+}
+
+impl Default for ReservedNames {
+    fn default() -> Self {
+        ReservedNames::new("world")
+    }
+}
+
+impl ReservedNames {
+    pub fn new(root: &str) -> ReservedNames {
+        ReservedNames {
+            root: root.to_string(),
+            others: HashSet::new(),
+        }
+    }
+
+    pub fn is_reserved(&self, frame_id: &str) -> bool {
+        frame_id == self.root || self.others.contains(frame_id)
+    }
+}
+
+// Limits and names of one space tree, trees in the same process can be configured differently
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeConfig {
+    // The longest chain of transforms that lookups, inserts and the visualization follow
+    pub max_chain_length: u64,
+    // New frames are rejected once the tree has this many frames
+    pub max_frames: usize,
+    pub history: HistoryConfig,
+    // Given to dynamic frames that arrive without a time to live, None keeps them forever
+    pub default_time_to_live: Option<Duration>,
+    pub names: ReservedNames,
+    // How many events are kept for subscribers that fall behind
+    pub events_capacity: usize,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        TreeConfig {
+            max_chain_length: 1000,
+            max_frames: 100_000,
+            history: HistoryConfig::default(),
+            default_time_to_live: None,
+            names: ReservedNames::default(),
+            events_capacity: 1024,
+        }
+    }
+}
+
+// What to do when the requested time falls between two samples of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InterpolationPolicy {
+    // Linear interpolation of the translation and spherical of the rotation
+    #[default]
+    Interpolate,
+    // Take the sample closest in time
+    Nearest,
+    // Take the latest sample taken before the requested time
+    LatestBefore,
+    // Fail unless there is a sample exactly at the requested time
+    Exact,
+}
+
+// What to do when the requested time is newer than the newest sample of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExtrapolationPolicy {
+    // Fail with an error naming the frame and how far ahead the lookup was, a stale
+    // pose is never returned unless a lookup asks for it
+    #[default]
+    Reject,
+    // Keep using the newest sample
+    Hold,
+    // Continue the motion between the two newest samples, but not further than the horizon
+    Extrapolate { horizon: Duration },
+}
+
+// Which samples time-aware lookups are resolved from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimelineSource {
+    // Only what the broadcasters reported
+    Observed,
+    // Only the planned timeline
+    Planned,
+    // Observations as long as they cover the requested time, continued by the plan after that
+    #[default]
+    PreferObserved,
+}
+
+// How time-aware lookups resolve the samples of every frame in the chain
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LookupPolicy {
+    pub interpolation: InterpolationPolicy,
+    pub extrapolation: ExtrapolationPolicy,
+    pub source: TimelineSource,
+}
+
+// What happens to the children of a removed frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalMode {
+    // Remove the whole subtree under the frame
+    Cascade,
+    // Attach the children to the parent of the removed frame, keeping their world poses
+    AdoptChildren,
+}
+
+// Frames that were removed, and frames that got a new parent because of a removal.
+// Changes that could not be written through to the storage are listed with the reason.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RemovalReport {
+    pub removed: Vec<TransformStamped>,
+    pub reparented: Vec<TransformStamped>,
+    pub unstored: Vec<(TransformStamped, String)>,
+}
+
+// The frame a reparent or a rename produced. Samples that could not be written through
+// to the storage are listed with the reason, they are changed in the tree anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeReport {
+    pub frame: TransformStamped,
+    pub unstored: Vec<(TransformStamped, String)>,
+}
+
+// What happened to every frame of an insert, accepted frames are described by the change they made.
+// Accepted samples older than the latest frame don't change it and are only recorded in the history.
+// Accepted frames that could not be written through to the storage are listed with the reason,
+// they are in the tree anyway.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InsertReport {
+    pub accepted: Vec<FrameEvent>,
+    pub late: Vec<TransformStamped>,
+    pub rejected: Vec<(TransformStamped, InsertError)>,
+    pub unstored: Vec<(TransformStamped, String)>,
+}
+
+// Which frames got a new planned timeline, and why the timelines of the others were rejected.
+// A timeline is rejected as a whole, together with its first invalid sample.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlanReport {
+    pub planned: Vec<String>,
+    pub rejected: Vec<(TransformStamped, InsertError)>,
+}
+
+// A change of the latest frames of a space tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameEvent {
+    Added(TransformStamped),
+    Updated(TransformStamped),
+    Removed(TransformStamped),
+    Reparented {
+        frame: TransformStamped,
+        previous_parent_frame_id: String,
+    },
+    // The frame was not updated within its time to live and was evicted
+    Expired(TransformStamped),
+}
+
+impl FrameEvent {
+    pub fn frame(&self) -> &TransformStamped {
+        match self {
+            FrameEvent::Added(frame)
+            | FrameEvent::Updated(frame)
+            | FrameEvent::Removed(frame)
+            | FrameEvent::Reparented { frame, .. }
+            | FrameEvent::Expired(frame) => frame,
+        }
+    }
+}
+
+// Which frame events a subscriber gets
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum FrameFilter {
+    #[default]
+    All,
+    Frames(HashSet<String>),
+    // The frame and everything below it
+    Subtree(String),
 }
 
 #[derive(StructOpt, Debug)]
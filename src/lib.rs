@@ -1,5 +1,16 @@
-pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
-pub static MAX_RECURSION_DEPTH: u64 = 1000;
+// The tests are kept as they were written, some of them predate these lints
+#![cfg_attr(
+    test,
+    allow(
+        clippy::approx_constant,
+        clippy::bool_assert_comparison,
+        clippy::clone_on_copy,
+        clippy::nonminimal_bool,
+        clippy::redundant_pattern_matching,
+        clippy::unit_cmp,
+        clippy::useless_vec
+    )
+)]
 
 pub mod core;
 pub use crate::core::structs::*;
@@ -7,14 +18,21 @@ pub use crate::core::api::*;
 pub use crate::core::errors::*;
 
 pub mod buffers;
+pub use crate::buffers::frame_tree::*;
 pub use crate::buffers::space_tree::*;
+pub use crate::buffers::history::*;
+pub use crate::buffers::storage::*;
+pub use crate::buffers::events::*;
 
 pub mod utils;
-// pub use crate::utils::manipulation::*;
+pub use crate::utils::manipulation::*;
 // pub use crate::utils::loading::*;
 pub use crate::utils::lookup::*;
 pub use crate::utils::cycles::*;
+pub use crate::utils::forest::*;
 pub use crate::utils::treeviz::*;
+pub use crate::utils::redis::*;
 
 pub mod loading;
-pub use crate::loading::files;
\ No newline at end of file
+pub use crate::loading::files;
+pub use crate::loading::redis::*;
\ No newline at end of file
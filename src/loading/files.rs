@@ -36,7 +36,11 @@ pub fn list_frames_in_dir(path: &str) -> Result<Vec<String>, Box<dyn std::error:
     Ok(scenario)
 }
 
-pub fn load_new_scenario(scenario: &Vec<String>) -> HashMap<String, TransformStamped> {
+// Frames that don't pass the same checks as inserted frames are skipped
+pub fn load_new_scenario(
+    scenario: &Vec<String>,
+    names: &ReservedNames,
+) -> HashMap<String, TransformStamped> {
     let mut transforms_stamped = HashMap::new();
 
     for path in scenario {
@@ -65,16 +69,27 @@ pub fn load_new_scenario(scenario: &Vec<String>) -> HashMap<String, TransformSta
             None => "".to_string(),
         };
 
-        transforms_stamped.insert(
-            child_frame_id.clone(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                child_frame_id,
-                parent_frame_id,
-                transform: json_transform_to_isometry(transform),
-                json_metadata,
-            },
-        );
+        // Frames in the scenario are static unless stated otherwise
+        let frame_kind = match extract_frame_kind(&json) {
+            Some(kind) => kind,
+            None => continue,
+        };
+
+        let frame = TransformStamped {
+            time_stamp: Instant::now(),
+            child_frame_id,
+            parent_frame_id,
+            transform: json_transform_to_isometry(transform),
+            json_metadata,
+            time_to_live: None,
+            frame_kind,
+        };
+        if let Err(reason) = check_frame(&frame, names) {
+            log::warn!(target: "space_time_trees", "Skipping '{}': {}", path, reason);
+            continue;
+        }
+
+        transforms_stamped.insert(frame.child_frame_id.clone(), frame);
     }
 
     transforms_stamped
@@ -130,6 +145,26 @@ fn extract_string_field(json: &Value, field: &str) -> Option<String> {
     }
 }
 
+fn extract_frame_kind(json: &Value) -> Option<FrameKind> {
+    match json.get("frame_kind") {
+        Some(value) => match serde_json::from_value(value.clone()) {
+            Ok(kind) => Some(kind),
+            Err(e) => {
+                log::warn!(target: "space_time_trees", 
+                    concat!(
+                        "Failed to deserialize 'frame_kind' field: '{}'. ",
+                        "Ensure the 'frame_kind' field is one of ",
+                        "'static', 'dynamic' or 'virtual'."
+                    ),
+                    e
+                );
+                None
+            }
+        },
+        None => Some(FrameKind::default()),
+    }
+}
+
 fn extract_transform(json: &Value) -> Option<JsonTransform> {
     match json.get("transform") {
         Some(value) => match serde_json::from_value(value.clone()) {
@@ -177,8 +212,16 @@ fn test_load_and_deserialize_from_file() {
     match frames {
         Ok(frames) => {
             // println!("Frames: {:?}", frames);
-            let scenario = load_new_scenario(&frames);
+            let scenario = load_new_scenario(&frames, &ReservedNames::default());
             println!("{:#?}", scenario);
+            assert_eq!(scenario.get("parent_b").unwrap().frame_kind, FrameKind::Static);
+            assert_eq!(scenario.get("child_7").unwrap().frame_kind, FrameKind::Dynamic);
+            assert_eq!(scenario.get("child_15").unwrap().frame_kind, FrameKind::Static);
+
+            // A stack with parent_b as its root can't load the frame of the same name
+            let scenario = load_new_scenario(&frames, &ReservedNames::new("parent_b"));
+            assert!(!scenario.contains_key("parent_b"));
+            assert!(scenario.contains_key("child_7"));
         }
         _ => panic!(),
     }
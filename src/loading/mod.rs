@@ -1,2 +1,2 @@
 pub mod files;
-// pub mod redis;
\ No newline at end of file
+pub mod redis;
\ No newline at end of file
@@ -0,0 +1,93 @@
+use futures::StreamExt;
+use log::{info, warn};
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::*;
+
+// Load the frames stored in Redis into a new tree that writes its changes back to Redis.
+// Changes made by other processes only show up in the tree while follow_redis_changes runs.
+pub fn load_tree_from_redis(
+    url: &str,
+    namespace: &str,
+    config: TreeConfig,
+) -> Result<(SpaceTree, Arc<RedisStorage>), ErrorMsg> {
+    let storage = Arc::new(RedisStorage::new(url, namespace, config.history)?);
+    let tree = SpaceTree::with_storage(config, storage.clone())?;
+    info!("Space tree loaded from Redis namespace '{}'", namespace);
+    Ok((tree, storage))
+}
+
+// Apply the changes that other trees make to the stored frames, using keyspace notifications.
+// Changes made through the same storage are already in the tree and are skipped.
+pub async fn follow_redis_changes(tree: &SpaceTree, storage: &RedisStorage) -> Result<(), ErrorMsg> {
+    let client = storage.client();
+    let mut connection = client.get_multiplexed_async_connection().await?;
+    enable_keyspace_notifications(&mut connection).await?;
+
+    let channel_prefix = format!(
+        "__keyspace@{}__:{}",
+        client.get_connection_info().redis.db,
+        storage.frame_key("")
+    );
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.psubscribe(format!("{}*", channel_prefix)).await?;
+    let mut messages = pubsub.on_message();
+
+    while let Some(message) = messages.next().await {
+        let frame_id = match message.get_channel_name().strip_prefix(&channel_prefix) {
+            Some(frame_id) => frame_id.to_string(),
+            None => continue,
+        };
+        let event: String = message.get_payload()?;
+        match event.as_str() {
+            "hset" => {
+                let fields: HashMap<String, String> = connection.hgetall(storage.frame_key(&frame_id)).await?;
+                if fields.is_empty() || fields.get("origin").map(String::as_str) == Some(storage.origin()) {
+                    continue;
+                }
+                match decode_frame(&fields) {
+                    Ok(frame) => tree.apply_stored_frame(frame),
+                    Err(e) => warn!("Stored frame '{}' is skipped: {}", frame_id, e),
+                }
+            }
+            // The notification can arrive after the frame was stored again, e.g. when
+            // this tree removed and re-inserted it, then the frame in Redis is the newer one
+            "del" | "expired" => {
+                let exists: bool = connection.exists(storage.frame_key(&frame_id)).await?;
+                if !exists {
+                    tree.apply_stored_removal(&frame_id)
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Err(ErrorMsg::new("Redis keyspace notifications stopped."))
+}
+
+// Keyspace notifications for generic and hash commands are needed, flags that are already set stay set
+async fn enable_keyspace_notifications(
+    connection: &mut redis::aio::MultiplexedConnection,
+) -> Result<(), ErrorMsg> {
+    let config: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(connection)
+        .await?;
+    let mut flags = config.get(1).cloned().unwrap_or_default();
+    for flag in ['K', 'g', 'h'] {
+        let covered = flag != 'K' && flags.contains('A');
+        if !flags.contains(flag) && !covered {
+            flags.push(flag);
+        }
+    }
+    redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(flags)
+        .query_async::<()>(connection)
+        .await?;
+    Ok(())
+}
@@ -1,24 +1,18 @@
 use crate::*;
-use std::collections::HashMap;
-
-pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
+use std::collections::{HashMap, HashSet};
 
 // Check for cycles in the tree segment starting from this frame
-pub fn is_cyclic(frame: &str, buffer: &HashMap<String, TransformStamped>) -> bool {
-    let mut stack = vec![frame.to_string()];
-    let mut visited = vec![];
+pub fn is_cyclic(frame: &str, buffer: &FrameTree) -> bool {
+    let mut stack = vec![frame];
+    let mut visited = HashSet::new();
 
     loop {
         match stack.pop() {
             Some(current_frame) => {
-                if visited.contains(&current_frame) && buffer.contains_key(&current_frame) {
+                if !visited.insert(current_frame) && buffer.contains_key(current_frame) {
                     break true;
                 } else {
-                    visited.push(current_frame.clone());
-
-                    for child in get_frame_children(&current_frame, buffer) {
-                        stack.push(child.1.child_frame_id);
-                    }
+                    stack.extend(buffer.child_ids(current_frame).map(String::as_str));
                 }
             }
             None => break false,
@@ -27,32 +21,95 @@ pub fn is_cyclic(frame: &str, buffer: &HashMap<String, TransformStamped>) -> boo
 }
 
 // Check for all cycles including all frames even if tree is segmented
-pub fn is_cyclic_all(frames: &HashMap<String, TransformStamped>) -> bool {
-    for (k, _) in frames {
-        if is_cyclic(k, frames) {
-            return true;
-        } else {
-            continue;
+pub fn is_cyclic_all(frames: &FrameTree) -> bool {
+    !find_cycles(frames).is_empty()
+}
+
+// All cycles of the tree, each in parent order starting with its alphabetically first frame.
+// Every frame has at most one parent, so following the parents from each frame once and
+// skipping the frames that were already followed finds all cycles in linear time.
+pub fn find_cycles(buffer: &FrameTree) -> Vec<Vec<String>> {
+    let mut cycles = vec![];
+    let mut done = HashSet::new();
+
+    for frame in buffer.keys() {
+        let mut path: Vec<&str> = vec![];
+        let mut positions = HashMap::new();
+        let mut current_frame = frame.as_str();
+
+        while !done.contains(current_frame) {
+            if let Some(&position) = positions.get(current_frame) {
+                cycles.push(ordered_cycle(&path[position..]));
+                break;
+            }
+            positions.insert(current_frame, path.len());
+            path.push(current_frame);
+            match buffer.parent(current_frame) {
+                Some(parent) => current_frame = parent,
+                None => break,
+            }
+        }
+        done.extend(path);
+    }
+    cycles.sort();
+    cycles
+}
+
+// Follow the parents of the frame and return the frames of the cycle it runs into, in parent order
+pub fn find_cycle_from(frame: &str, buffer: &FrameTree) -> Option<Vec<String>> {
+    let mut path: Vec<&str> = vec![];
+    let mut positions = HashMap::new();
+    let mut current_frame = frame;
+
+    loop {
+        if let Some(&position) = positions.get(current_frame) {
+            return Some(path[position..].iter().map(|f| f.to_string()).collect());
         }
+        positions.insert(current_frame, path.len());
+        path.push(current_frame);
+        current_frame = buffer.parent(current_frame)?;
     }
-    false
+}
+
+// The same cycle always starts with the same frame, no matter where it was entered
+fn ordered_cycle(cycle: &[&str]) -> Vec<String> {
+    let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+    cycle[first..]
+        .iter()
+        .chain(&cycle[..first])
+        .map(|f| f.to_string())
+        .collect()
 }
 
 // check if adding the frame to the tree would produce a cycle
 pub fn check_would_produce_cycle(
     frame: &TransformStamped,
-    buffer: &HashMap<String, TransformStamped>,
+    buffer: &FrameTree,
 ) -> bool {
-    let mut buffer_local = buffer.clone();
-    buffer_local.insert(frame.child_frame_id.clone(), frame.clone());
-    is_cyclic_all(&buffer_local)
+    cycle_produced_by(frame, buffer).is_some()
+}
+
+// The cycle that adding the frame would close, in parent order starting with the frame.
+// Only the chain above the new parent is walked, cycles elsewhere in the tree are ignored.
+pub fn cycle_produced_by(frame: &TransformStamped, buffer: &FrameTree) -> Option<Vec<String>> {
+    let mut cycle = vec![frame.child_frame_id.clone()];
+    let mut visited = HashSet::new();
+    let mut current_frame = frame.parent_frame_id.as_str();
+
+    while visited.insert(current_frame) {
+        if current_frame == frame.child_frame_id {
+            return Some(cycle);
+        }
+        cycle.push(current_frame.to_string());
+        current_frame = buffer.parent(current_frame)?;
+    }
+    None
 }
 
 #[cfg(test)]
 mod tests {
 
     use nalgebra::Isometry3;
-    use std::collections::HashMap;
     use tokio::time::Instant;
 
     use crate::*;
@@ -64,6 +121,8 @@ mod tests {
             child_frame_id: "dummy_1".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
@@ -74,6 +133,8 @@ mod tests {
             child_frame_id: "dummy_2".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
@@ -84,13 +145,15 @@ mod tests {
             child_frame_id: "dummy_3".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
     #[test]
     fn test_is_not_cyclic() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
 
         //          w
         //          |
@@ -99,7 +162,7 @@ mod tests {
         let res = is_cyclic("dummy_1", &buffer);
         assert_eq!(res, false);
 
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
+        buffer.insert(dummy_2_frame());
 
         //          w
         //          |
@@ -117,19 +180,18 @@ mod tests {
 
     #[test]
     fn test_is_cyclic() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
-        buffer.insert(
-            "dummy_1".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "dummy_2".to_string(),
-                child_frame_id: "dummy_1".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
+        buffer.insert(dummy_2_frame());
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "dummy_2".to_string(),
+            child_frame_id: "dummy_1".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         //          w
         //          
@@ -147,10 +209,10 @@ mod tests {
 
     #[test]
     fn test_is_cyclic_triangle() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
-        buffer.insert("dummy_3".to_string(), dummy_3_frame());
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
+        buffer.insert(dummy_2_frame());
+        buffer.insert(dummy_3_frame());
 
         //          w
         //          |
@@ -167,16 +229,15 @@ mod tests {
         let res = is_cyclic("dummy_3", &buffer);
         assert_eq!(res, false);
 
-        buffer.insert(
-            "dummy_1".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "dummy_3".to_string(),
-                child_frame_id: "dummy_1".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "dummy_3".to_string(),
+            child_frame_id: "dummy_1".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         //          w
         //          
@@ -197,10 +258,10 @@ mod tests {
 
     #[test]
     fn test_is_cyclic_all() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
-        buffer.insert("dummy_3".to_string(), dummy_3_frame());
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
+        buffer.insert(dummy_2_frame());
+        buffer.insert(dummy_3_frame());
 
         //          w
         //          |
@@ -211,27 +272,25 @@ mod tests {
         let res = is_cyclic_all(&buffer);
         assert_eq!(res, false);
 
-        buffer.insert(
-            "dummy_5".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "dummy_4".to_string(),
-                child_frame_id: "dummy_5".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "dummy_4".to_string(),
+            child_frame_id: "dummy_5".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
-        buffer.insert(
-            "dummy_6".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "dummy_5".to_string(),
-                child_frame_id: "dummy_6".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "dummy_5".to_string(),
+            child_frame_id: "dummy_6".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         //          w           d4
         //          |           |
@@ -242,16 +301,15 @@ mod tests {
         let res = is_cyclic_all(&buffer);
         assert_eq!(res, false);
 
-        buffer.insert(
-            "dummy_4".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "dummy_6".to_string(),
-                child_frame_id: "dummy_4".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "dummy_6".to_string(),
+            child_frame_id: "dummy_4".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         //          w           d4
         //          |          /  \
@@ -262,16 +320,15 @@ mod tests {
         let res = is_cyclic_all(&buffer);
         assert_eq!(res, true);
 
-        buffer.insert(
-            "dummy_4".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "world".to_string(),
-                child_frame_id: "dummy_4".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        buffer.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "world".to_string(),
+            child_frame_id: "dummy_4".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         //          w --------- d4
         //          |          /  
@@ -283,12 +340,50 @@ mod tests {
         assert_eq!(res, false);
     }
 
+    #[test]
+    fn test_find_cycles() {
+        let frame = |parent: &str, child: &str| TransformStamped {
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            ..dummy_1_frame()
+        };
+        let mut buffer = FrameTree::from_iter(vec![
+            dummy_1_frame(),
+            dummy_2_frame(),
+            dummy_3_frame(),
+            frame("c", "a"),
+            frame("a", "b"),
+            frame("b", "c"),
+            frame("b", "tail"),
+        ]);
+
+        //          w           a
+        //          |          /  \
+        //          d1        c -- b -- tail
+        //         /
+        //       d2 -- d3
+
+        assert_eq!(find_cycles(&buffer), vec![vec!["a", "c", "b"]]);
+        assert_eq!(find_cycle_from("tail", &buffer), Some(vec!["b".to_string(), "a".to_string(), "c".to_string()]));
+        assert_eq!(find_cycle_from("dummy_3", &buffer), None);
+
+        buffer.insert(frame("dummy_3", "dummy_1"));
+        assert_eq!(
+            find_cycles(&buffer),
+            vec![vec!["a", "c", "b"], vec!["dummy_1", "dummy_3", "dummy_2"]]
+        );
+        assert_eq!(
+            cycle_produced_by(&frame("tail", "c"), &buffer),
+            Some(vec!["c".to_string(), "tail".to_string(), "b".to_string(), "a".to_string()])
+        );
+    }
+
     #[test]
     fn test_would_produce_cycle() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
-        buffer.insert("dummy_3".to_string(), dummy_3_frame());
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
+        buffer.insert(dummy_2_frame());
+        buffer.insert(dummy_3_frame());
 
         //          w
         //          |
@@ -312,6 +407,8 @@ mod tests {
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
                 json_metadata: String::default(),
+                time_to_live: None,
+                frame_kind: FrameKind::Dynamic,
             }, 
             &buffer), false
         );
@@ -323,6 +420,8 @@ mod tests {
                 child_frame_id: "dummy_1".to_string(),
                 transform: Isometry3::default(),
                 json_metadata: String::default(),
+                time_to_live: None,
+                frame_kind: FrameKind::Dynamic,
             }, 
             &buffer), true
         );
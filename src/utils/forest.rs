@@ -0,0 +1,156 @@
+use crate::*;
+use std::collections::HashSet;
+
+// A buffer can hold several independent trees, e.g. a robot and a camera rig that are only
+// joined after calibration. Every tree has one root, a frame that is a parent but has no parent
+// itself. Frames of a cycle are not below any root and don't belong to any component.
+
+// Roots of all trees in alphabetical order
+pub fn find_roots(buffer: &FrameTree) -> Vec<String> {
+    let mut roots = buffer
+        .parent_ids()
+        .filter(|frame_id| !buffer.contains_key(*frame_id))
+        .cloned()
+        .collect::<Vec<String>>();
+    roots.sort();
+    roots
+}
+
+// The root of the tree the frame is in, None if the frame doesn't exist or runs into a cycle
+pub fn find_root(frame: &str, buffer: &FrameTree) -> Option<String> {
+    if !buffer.contains_frame(frame) {
+        return None;
+    }
+    let mut visited = HashSet::new();
+    let mut current_frame = frame;
+    while let Some(parent) = buffer.parent(current_frame) {
+        if !visited.insert(current_frame) {
+            return None;
+        }
+        current_frame = parent;
+    }
+    Some(current_frame.to_string())
+}
+
+// Number of transforms from the frame up to the root of its tree, or into a cycle
+pub fn chain_length(frame: &str, buffer: &FrameTree) -> u64 {
+    let mut visited = HashSet::new();
+    let mut current_frame = frame;
+    while let Some(parent) = buffer.parent(current_frame) {
+        if !visited.insert(current_frame) {
+            break;
+        }
+        current_frame = parent;
+    }
+    visited.len() as u64
+}
+
+// Number of transforms from the frame down to its deepest descendant, a cycle through
+// the frame is only followed once
+pub fn subtree_height(frame: &str, buffer: &FrameTree) -> u64 {
+    let mut height = 0;
+    let mut visited = HashSet::from([frame.to_string()]);
+    let mut level = vec![frame.to_string()];
+    loop {
+        level = level
+            .iter()
+            .flat_map(|frame_id| buffer.child_ids(frame_id).cloned())
+            .filter(|child_id| visited.insert(child_id.clone()))
+            .collect();
+        if level.is_empty() {
+            return height;
+        }
+        height += 1;
+    }
+}
+
+// The root and all frames below it, parents before their children
+pub fn component_frames(root: &str, buffer: &FrameTree) -> Vec<String> {
+    let mut frames = vec![root.to_string()];
+    let mut next = 0;
+    while next < frames.len() {
+        let children = buffer.child_ids(&frames[next]).cloned().collect::<Vec<String>>();
+        frames.extend(children);
+        next += 1;
+    }
+    frames
+}
+
+// The frames of every tree, in the order of their roots
+pub fn find_components(buffer: &FrameTree) -> Vec<Vec<String>> {
+    find_roots(buffer)
+        .iter()
+        .map(|root| component_frames(root, buffer))
+        .collect()
+}
+
+// The tree below the root on its own, to look it up or visualize it separately
+pub fn component_tree(root: &str, buffer: &FrameTree) -> FrameTree {
+    component_frames(root, buffer)
+        .iter()
+        .filter_map(|frame_id| buffer.get(frame_id))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::Isometry3;
+    use std::sync::{Arc, RwLock};
+    use tokio::time::Instant;
+
+    use crate::*;
+
+    fn frame(parent: &str, child: &str, x: f64) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::translation(x, 0.0, 0.0),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_forest() {
+        let mut buffer = FrameTree::from_iter(vec![
+            frame("robot_base", "flange", 1.0),
+            frame("flange", "gripper", 0.1),
+            frame("rig", "camera_left", 0.2),
+            frame("rig", "camera_right", -0.2),
+            frame("a", "b", 0.0),
+            frame("b", "a", 0.0),
+        ]);
+
+        assert_eq!(find_roots(&buffer), vec!["rig", "robot_base"]);
+        assert_eq!(
+            find_components(&buffer),
+            vec![
+                vec!["rig", "camera_left", "camera_right"],
+                vec!["robot_base", "flange", "gripper"]
+            ]
+        );
+        assert_eq!(find_root("gripper", &buffer), Some("robot_base".to_string()));
+        assert_eq!(find_root("rig", &buffer), Some("rig".to_string()));
+        assert_eq!(find_root("a", &buffer), None);
+        assert_eq!(find_root("unknown", &buffer), None);
+        assert_eq!(chain_length("gripper", &buffer), 2);
+        assert_eq!(subtree_height("robot_base", &buffer), 2);
+        assert_eq!(subtree_height("a", &buffer), 1);
+
+        // Each component can be looked up on its own
+        let rig = Arc::new(RwLock::new(component_tree("rig", &buffer)));
+        assert_eq!(rig.read().unwrap().len(), 2);
+        let pose = lookup_transform_lca("camera_left", "camera_right", &rig, &TreeConfig::default()).unwrap();
+        assert!((pose.transform.translation.x + 0.4).abs() < 1e-9);
+
+        // Calibration joins the rig to the robot
+        buffer.insert(frame("robot_base", "rig", 0.5));
+        assert_eq!(find_roots(&buffer), vec!["robot_base"]);
+        assert_eq!(find_root("camera_left", &buffer), Some("robot_base".to_string()));
+        assert_eq!(find_components(&buffer)[0].len(), 6);
+    }
+}
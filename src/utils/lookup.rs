@@ -1,143 +1,486 @@
-use crate::{is_cyclic_all, TransformStamped};
+use crate::{
+    find_cycle_from, FrameKind, FrameTree, LookupError, LookupPolicy, TransformHistory, TransformStamped,
+    TreeConfig,
+};
 use nalgebra::Isometry3;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 
 use tokio::time::Instant;
 
-pub static MAX_TRANSFORM_CHAIN: u64 = 1000;
-
 pub fn isometry_chain_product(vec: Vec<Isometry3<f64>>) -> Isometry3<f64> {
     vec.iter().fold(Isometry3::identity(), |a, &b| a * b)
 }
 
+// Compose the latest sample of every hop, no matter how old each of them is. Nothing is
+// interpolated, the time stamp of the result is that of its oldest sample. Use
+// lookup_transform_at to get the chain as it was at a common time.
 pub fn lookup_transform(
     parent_frame_id: &str,
     child_frame_id: &str,
     root_frame_id: &str,
-    buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
-) -> Option<TransformStamped> {
-    let buffer_local = buffer.lock().unwrap().clone();
-    let mut chain = vec![];
-    if !is_cyclic_all(&buffer_local) {
-        match parent_to_root(parent_frame_id, root_frame_id, &buffer_local) {
-            Some(up_chain) => match root_to_child(child_frame_id, root_frame_id, &buffer_local) {
-                Some(down_chain) => {
-                    chain.push(up_chain);
-                    chain.push(down_chain);
-                    let iso_3 = isometry_chain_product(chain);
-                    Some(TransformStamped {
-                        time_stamp: Instant::now(),
-                        parent_frame_id: parent_frame_id.to_string(),
-                        child_frame_id: child_frame_id.to_string(),
-                        transform: iso_3,
-                        json_metadata: "".to_string(),
-                    })
-                }
-                None => None,
-            },
-            None => None,
-        }
-    } else {
-        None
+    buffer: &Arc<RwLock<FrameTree>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, LookupError> {
+    let buffer_local = buffer.read().unwrap();
+    if parent_frame_id != root_frame_id && !frame_exists(parent_frame_id, &buffer_local) {
+        return Err(LookupError::UnknownTargetFrame(parent_frame_id.to_string()));
+    }
+    if child_frame_id != root_frame_id && !frame_exists(child_frame_id, &buffer_local) {
+        return Err(LookupError::UnknownSourceFrame(child_frame_id.to_string()));
     }
+
+    let as_lookup_error = |error| as_lookup_error(error, parent_frame_id, child_frame_id);
+    let up_chain = parent_to_root(parent_frame_id, root_frame_id, &buffer_local, config).map_err(as_lookup_error)?;
+    let down_chain = root_to_child(child_frame_id, root_frame_id, &buffer_local, config).map_err(as_lookup_error)?;
+    let iso_3 = isometry_chain_product(vec![up_chain, down_chain]);
+    // The result is only as recent as the oldest frame it was composed from
+    let time_stamp = [parent_frame_id, child_frame_id]
+        .iter()
+        .filter_map(|frame| {
+            oldest_time_stamp_to_root(frame, root_frame_id, &buffer_local, config.max_chain_length)
+        })
+        .min()
+        .unwrap_or_else(Instant::now);
+    Ok(TransformStamped {
+        time_stamp,
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform: iso_3,
+        json_metadata: "".to_string(),
+        time_to_live: None,
+        frame_kind: FrameKind::Virtual,
+    })
+}
+
+// A frame exists if it is in the buffer or if some frame in the buffer is its child
+pub fn frame_exists(frame_id: &str, buffer: &FrameTree) -> bool {
+    buffer.contains_frame(frame_id)
 }
 
-// Go upstream to the root
+// Go upstream to the root, the result is the pose of the root in the parent frame
 pub fn parent_to_root(
     parent_frame_id: &str,
     root_frame_id: &str,
-    buffer: &HashMap<String, TransformStamped>,
-) -> Option<Isometry3<f64>> {
-    let mut current_parent = parent_frame_id.to_string();
-    let mut path = vec![];
-    let mut length = 0;
+    buffer: &FrameTree,
+    config: &TreeConfig,
+) -> Result<Isometry3<f64>, LookupError> {
+    match frame_to_root(parent_frame_id, root_frame_id, buffer, config.max_chain_length) {
+        Ok(transform) => Ok(transform.inverse()),
+        Err(LookupError::UnknownSourceFrame(frame)) => Err(LookupError::UnknownTargetFrame(frame)),
+        Err(error) => Err(error),
+    }
+}
 
-    if parent_frame_id == root_frame_id {
-        return Some(Isometry3::identity())
-    }
-
-    let res = loop {
-        if length >= MAX_TRANSFORM_CHAIN {
-            break None;
-        } else {
-            length = length + 1;
-            match buffer.get(&current_parent) {
-                Some(parent) => {
-                    path.push(parent.transform.inverse());
-                    if parent.parent_frame_id == root_frame_id {
-                        break Some(path);
-                    } else {
-                        current_parent = parent.parent_frame_id.to_string();
-                    }
-                }
-                None => break None,
+// Path from the root down to the child, found by walking up from the child
+pub fn root_to_child(
+    child_frame_id: &str,
+    root_frame_id: &str,
+    buffer: &FrameTree,
+    config: &TreeConfig,
+) -> Result<Isometry3<f64>, LookupError> {
+    frame_to_root(child_frame_id, root_frame_id, buffer, config.max_chain_length)
+}
+
+// Follow the parents up to the root, the result is the pose of the frame in the root frame
+fn frame_to_root(
+    frame_id: &str,
+    root_frame_id: &str,
+    buffer: &FrameTree,
+    max_chain_length: u64,
+) -> Result<Isometry3<f64>, LookupError> {
+    let mut current_frame = frame_id;
+    let mut visited = HashSet::new();
+    let mut transform = Isometry3::identity();
+
+    while current_frame != root_frame_id {
+        if visited.len() as u64 >= max_chain_length {
+            return Err(LookupError::ChainTooLong {
+                frame: frame_id.to_string(),
+                limit: max_chain_length,
+            });
+        }
+        if !visited.insert(current_frame) {
+            return Err(LookupError::CycleDetected(
+                find_cycle_from(current_frame, buffer).unwrap_or_default(),
+            ));
+        }
+        match buffer.get(current_frame) {
+            Some(frame) => {
+                transform = frame.transform * transform;
+                current_frame = &frame.parent_frame_id;
+            }
+            None if visited.len() == 1 && !frame_exists(frame_id, buffer) => {
+                return Err(LookupError::UnknownSourceFrame(frame_id.to_string()))
+            }
+            None => {
+                return Err(LookupError::DisconnectedFrames {
+                    source: frame_id.to_string(),
+                    target: root_frame_id.to_string(),
+                })
             }
         }
-    };
-
-    match res {
-        Some(chain) => Some(isometry_chain_product(chain)),
-        None => None,
     }
+
+    Ok(transform)
 }
 
-// BFS to get the path to the child
-pub fn root_to_child(
+// Same as lookup_transform, but every hop of the chain is taken as it was at the requested time
+pub fn lookup_transform_at(
+    parent_frame_id: &str,
     child_frame_id: &str,
     root_frame_id: &str,
-    buffer: &HashMap<String, TransformStamped>,
-) -> Option<Isometry3<f64>> {
+    time: Instant,
+    policy: &LookupPolicy,
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, LookupError> {
+    let buffer_local = buffer.read().unwrap();
+    let as_lookup_error = |error| as_lookup_error(error, parent_frame_id, child_frame_id);
+    let up_chain = frame_to_root_at(parent_frame_id, root_frame_id, time, policy, &buffer_local, config)
+        .map_err(as_target_error)
+        .map_err(as_lookup_error)?;
+    let down_chain = frame_to_root_at(child_frame_id, root_frame_id, time, policy, &buffer_local, config)
+        .map_err(as_lookup_error)?;
+    Ok(TransformStamped {
+        time_stamp: time,
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform: up_chain.inverse() * down_chain,
+        json_metadata: "".to_string(),
+        time_to_live: None,
+        frame_kind: FrameKind::Virtual,
+    })
+}
+
+// Transform from the source frame at the source time to the target frame at the target time,
+// assuming that the fixed frame does not move in between. The source frame is first expressed
+// in the fixed frame at the source time and then in the target frame at the target time.
+#[allow(clippy::too_many_arguments)]
+pub fn lookup_transform_full(
+    target_frame_id: &str,
+    target_time: Instant,
+    source_frame_id: &str,
+    source_time: Instant,
+    fixed_frame_id: &str,
+    root_frame_id: &str,
+    policy: &LookupPolicy,
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, LookupError> {
+    let buffer_local = buffer.read().unwrap();
+    let to_root = |frame_id: &str, time: Instant| {
+        frame_to_root_at(frame_id, root_frame_id, time, policy, &buffer_local, config)
+            .map_err(|error| as_lookup_error(error, target_frame_id, source_frame_id))
+    };
+    let fixed_at_source = to_root(fixed_frame_id, source_time)?;
+    let source = to_root(source_frame_id, source_time)?;
+    let fixed_at_target = to_root(fixed_frame_id, target_time)?;
+    let target = to_root(target_frame_id, target_time).map_err(as_target_error)?;
+    let fixed_to_source = fixed_at_source.inverse() * source;
+    let target_to_fixed = target.inverse() * fixed_at_target;
+    Ok(TransformStamped {
+        time_stamp: target_time,
+        parent_frame_id: target_frame_id.to_string(),
+        child_frame_id: source_frame_id.to_string(),
+        transform: target_to_fixed * fixed_to_source,
+        json_metadata: "".to_string(),
+        time_to_live: None,
+        frame_kind: FrameKind::Virtual,
+    })
+}
+
+// Go upstream to the root using the samples valid at the requested time,
+// the result is the pose of the frame expressed in the root frame
+pub fn frame_to_root_at(
+    frame_id: &str,
+    root_frame_id: &str,
+    time: Instant,
+    policy: &LookupPolicy,
+    buffer: &HashMap<String, TransformHistory>,
+    config: &TreeConfig,
+) -> Result<Isometry3<f64>, LookupError> {
+    let mut current_frame = frame_id.to_string();
+    let mut path = vec![];
+    let mut visited = vec![];
     let mut length = 0;
-    let mut stack = vec![];
-    get_frame_children(root_frame_id, buffer)
-        .iter()
-        .for_each(|(k, v)| stack.push((k.to_string(), vec![k.to_string()], vec![v.transform])));
-
-    let res = loop {
-        if length >= MAX_TRANSFORM_CHAIN {
-            break None;
-        } else {
-            length = length + 1;
-            match stack.pop() {
-                Some((frame, path, chain)) => {
-                    if frame == child_frame_id {
-                        break Some(chain);
-                    } else {
-                        get_frame_children(&frame, buffer)
-                            .iter()
-                            .for_each(|(k, v)| {
-                                let mut prev_path = path.clone();
-                                let mut prev_chain = chain.clone();
-                                prev_path.push(k.clone());
-                                prev_chain.push(v.transform);
-                                stack.insert(
-                                    0,
-                                    (k.to_string(), prev_path.clone(), prev_chain.clone()),
-                                )
-                            })
-                    }
-                }
-                None => break None,
+
+    while current_frame != root_frame_id {
+        if length >= config.max_chain_length {
+            return Err(LookupError::ChainTooLong {
+                frame: frame_id.to_string(),
+                limit: config.max_chain_length,
+            });
+        }
+        length += 1;
+        if let Some(position) = visited.iter().position(|frame| *frame == current_frame) {
+            return Err(LookupError::CycleDetected(visited.split_off(position)));
+        }
+        let sample = match buffer.get(&current_frame) {
+            Some(history) => history.resolve_at(time, policy)?,
+            None if length == 1 && !history_frame_exists(frame_id, buffer) => {
+                return Err(LookupError::UnknownSourceFrame(frame_id.to_string()))
+            }
+            None => {
+                return Err(LookupError::DisconnectedFrames {
+                    source: frame_id.to_string(),
+                    target: root_frame_id.to_string(),
+                })
             }
+        };
+        visited.push(current_frame);
+        path.push(sample.transform);
+        current_frame = sample.parent_frame_id;
+    }
+
+    path.reverse();
+    Ok(isometry_chain_product(path))
+}
+
+// The frame walked up from is reported as the source, when it is the target of the lookup
+// the error should say so
+fn as_target_error(error: LookupError) -> LookupError {
+    match error {
+        LookupError::UnknownSourceFrame(frame) => LookupError::UnknownTargetFrame(frame),
+        error => error,
+    }
+}
+
+// The hops report the frame they walked up from and the root, a lookup that can't
+// connect its frames reports its own source and target
+fn as_lookup_error(error: LookupError, parent_frame_id: &str, child_frame_id: &str) -> LookupError {
+    match error {
+        LookupError::DisconnectedFrames { .. } => LookupError::DisconnectedFrames {
+            source: child_frame_id.to_string(),
+            target: parent_frame_id.to_string(),
+        },
+        error => error,
+    }
+}
+
+// A frame exists if it has samples or if it was the parent of some sample
+pub fn history_frame_exists(frame_id: &str, buffer: &HashMap<String, TransformHistory>) -> bool {
+    buffer.contains_key(frame_id)
+        || buffer.values().any(|history| {
+            history
+                .samples()
+                .iter()
+                .chain(history.planned())
+                .any(|sample| sample.parent_frame_id == frame_id)
+        })
+}
+
+// Lookup that doesn't need a root, the chain is composed only of the hops up from both
+// frames to their lowest common ancestor. Works for frames in any connected subtree.
+pub fn lookup_transform_lca(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    buffer: &Arc<RwLock<FrameTree>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, LookupError> {
+    let buffer_local = buffer.read().unwrap();
+    let resolve = |frame_id: &str| Ok(buffer_local.get(frame_id).cloned());
+    let parent_of = |frame_id: &str| buffer_local.parent(frame_id).map(str::to_string);
+    if !frame_exists(parent_frame_id, &buffer_local) {
+        return Err(LookupError::UnknownTargetFrame(parent_frame_id.to_string()));
+    }
+    if !frame_exists(child_frame_id, &buffer_local) {
+        return Err(LookupError::UnknownSourceFrame(child_frame_id.to_string()));
+    }
+    let (transform, time_stamp) =
+        compose_through_lca(parent_frame_id, child_frame_id, resolve, parent_of, config.max_chain_length)?;
+    Ok(TransformStamped {
+        time_stamp: time_stamp.unwrap_or_else(Instant::now),
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform,
+        json_metadata: "".to_string(),
+        time_to_live: None,
+        frame_kind: FrameKind::Virtual,
+    })
+}
+
+// Same as lookup_transform_lca, but every hop of the chain is taken as it was at the requested time
+pub fn lookup_transform_lca_at(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    time: Instant,
+    policy: &LookupPolicy,
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, LookupError> {
+    let buffer_local = buffer.read().unwrap();
+    let resolve = |frame_id: &str| match buffer_local.get(frame_id) {
+        Some(history) => history.resolve_at(time, policy).map(Some),
+        None => Ok(None),
+    };
+    let parent_of = |frame_id: &str| {
+        buffer_local
+            .get(frame_id)
+            .and_then(|history| history.resolve_parent_at(time, policy))
+    };
+    // Checking if the frames exist is expensive without an index, so only do it when the lookup fails
+    // or when a frame is looked up in itself, which needs no samples at all
+    if parent_frame_id == child_frame_id && !history_frame_exists(child_frame_id, &buffer_local) {
+        return Err(LookupError::UnknownSourceFrame(child_frame_id.to_string()));
+    }
+    let composed = compose_through_lca(parent_frame_id, child_frame_id, resolve, parent_of, config.max_chain_length);
+    let (transform, _) = composed.map_err(|error| {
+        match error {
+            LookupError::DisconnectedFrames { .. } if !history_frame_exists(parent_frame_id, &buffer_local) => {
+                LookupError::UnknownTargetFrame(parent_frame_id.to_string())
+            }
+            LookupError::DisconnectedFrames { .. } if !history_frame_exists(child_frame_id, &buffer_local) => {
+                LookupError::UnknownSourceFrame(child_frame_id.to_string())
+            }
+            error => error,
+        }
+    })?;
+    Ok(TransformStamped {
+        time_stamp: time,
+        parent_frame_id: parent_frame_id.to_string(),
+        child_frame_id: child_frame_id.to_string(),
+        transform,
+        json_metadata: "".to_string(),
+        time_to_live: None,
+        frame_kind: FrameKind::Virtual,
+    })
+}
+
+// A step on the way up from a frame: the ancestor, the pose of the frame in the ancestor
+// and the time stamp of the oldest sample used to get there
+type Ancestor = (String, Isometry3<f64>, Option<Instant>);
+
+// Ancestors of the frame, starting with the frame itself and ending with the root of its tree
+// or with the first ancestor where the walk should stop
+fn frame_to_ancestors<F, S>(
+    frame_id: &str,
+    resolve: &F,
+    stop: S,
+    max_chain_length: u64,
+) -> Result<Vec<Ancestor>, LookupError>
+where
+    F: Fn(&str) -> Result<Option<TransformStamped>, LookupError>,
+    S: Fn(&str) -> bool,
+{
+    let mut ancestors: Vec<Ancestor> = vec![(frame_id.to_string(), Isometry3::identity(), None)];
+    let mut length = 0;
+
+    while !stop(&ancestors[ancestors.len() - 1].0) {
+        let sample = match resolve(&ancestors[ancestors.len() - 1].0)? {
+            Some(sample) => sample,
+            None => break,
+        };
+        if length >= max_chain_length {
+            return Err(LookupError::ChainTooLong {
+                frame: frame_id.to_string(),
+                limit: max_chain_length,
+            });
+        }
+        length += 1;
+        if let Some(position) = ancestors
+            .iter()
+            .position(|(ancestor, _, _)| *ancestor == sample.parent_frame_id)
+        {
+            return Err(LookupError::CycleDetected(
+                ancestors
+                    .split_off(position)
+                    .into_iter()
+                    .map(|(ancestor, _, _)| ancestor)
+                    .collect(),
+            ));
+        }
+        let (_, pose, oldest) = &ancestors[ancestors.len() - 1];
+        let oldest = Some(oldest.map_or(sample.time_stamp, |t| t.min(sample.time_stamp)));
+        ancestors.push((sample.parent_frame_id, sample.transform * pose, oldest));
+    }
+
+    Ok(ancestors)
+}
+
+// Ids of all ancestors of the frame up to the root of its tree, starting with the frame itself.
+// Only the parents are followed, the poses on the way don't have to be resolvable.
+fn ancestor_ids<P>(frame_id: &str, parent_of: &P, max_chain_length: u64) -> Result<Vec<String>, LookupError>
+where
+    P: Fn(&str) -> Option<String>,
+{
+    let mut ancestors = vec![frame_id.to_string()];
+    while let Some(parent) = parent_of(&ancestors[ancestors.len() - 1]) {
+        if ancestors.len() as u64 > max_chain_length {
+            return Err(LookupError::ChainTooLong {
+                frame: frame_id.to_string(),
+                limit: max_chain_length,
+            });
         }
+        if let Some(position) = ancestors.iter().position(|ancestor| *ancestor == parent) {
+            return Err(LookupError::CycleDetected(ancestors.split_off(position)));
+        }
+        ancestors.push(parent);
+    }
+    Ok(ancestors)
+}
+
+// Pose of the child in the parent frame composed through their lowest common ancestor,
+// together with the time stamp of the oldest sample that was used. The hops above the
+// lowest common ancestor are not part of the pose, so only their parents are looked at.
+fn compose_through_lca<F, P>(
+    parent_frame_id: &str,
+    child_frame_id: &str,
+    resolve: F,
+    parent_of: P,
+    max_chain_length: u64,
+) -> Result<(Isometry3<f64>, Option<Instant>), LookupError>
+where
+    F: Fn(&str) -> Result<Option<TransformStamped>, LookupError>,
+    P: Fn(&str) -> Option<String>,
+{
+    let disconnected = || LookupError::DisconnectedFrames {
+        source: child_frame_id.to_string(),
+        target: parent_frame_id.to_string(),
+    };
+    let parent_ids = ancestor_ids(parent_frame_id, &parent_of, max_chain_length)?;
+    let is_parent_ancestor = |frame_id: &str| parent_ids.iter().any(|ancestor| ancestor == frame_id);
+    let child_ancestors = frame_to_ancestors(child_frame_id, &resolve, is_parent_ancestor, max_chain_length)?;
+    let (lca, child_pose, child_oldest) = &child_ancestors[child_ancestors.len() - 1];
+    if !is_parent_ancestor(lca) {
+        return Err(disconnected());
+    }
+    let parent_ancestors = frame_to_ancestors(parent_frame_id, &resolve, |frame_id| frame_id == lca, max_chain_length)?;
+    let (reached, parent_pose, parent_oldest) = &parent_ancestors[parent_ancestors.len() - 1];
+    if reached != lca {
+        return Err(disconnected());
+    }
+    let oldest = match (parent_oldest, child_oldest) {
+        (Some(a), Some(b)) => Some(*a.min(b)),
+        (a, b) => a.or(*b),
     };
+    Ok((parent_pose.inverse() * child_pose, oldest))
+}
 
-    match res {
-        Some(chain) => Some(isometry_chain_product(chain)),
-        None => None,
+// The time of the oldest sample on the way from the frame up to the root
+fn oldest_time_stamp_to_root(
+    frame_id: &str,
+    root_frame_id: &str,
+    buffer: &FrameTree,
+    max_chain_length: u64,
+) -> Option<Instant> {
+    let mut current_frame = frame_id;
+    let mut oldest = None::<Instant>;
+    let mut length = 0;
+    while current_frame != root_frame_id && length < max_chain_length {
+        length += 1;
+        let frame = buffer.get(current_frame)?;
+        oldest = Some(oldest.map_or(frame.time_stamp, |t| t.min(frame.time_stamp)));
+        current_frame = &frame.parent_frame_id;
     }
+    oldest
 }
 
 // The frame whose children we are searching for don't have to exist in the transform buffer
-pub fn get_frame_children(
-    frame: &str,
-    buffer: &HashMap<String, TransformStamped>,
-) -> Vec<(String, TransformStamped)> {
+pub fn get_frame_children(frame: &str, buffer: &FrameTree) -> Vec<(String, TransformStamped)> {
     buffer
-        .iter()
-        .filter(|(_, v)| v.parent_frame_id == frame)
-        .map(|(k, v)| (k.clone(), v.clone()))
+        .children(frame)
+        .map(|child| (child.child_frame_id.clone(), child.clone()))
         .collect()
 }
 
@@ -146,23 +489,20 @@ mod tests {
 
     use nalgebra::{Isometry3, Quaternion, Translation, UnitQuaternion, Vector3};
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use tokio::time::Instant;
+    use std::sync::{Arc, RwLock};
+    use tokio::time::{Duration, Instant};
 
     use crate::*;
 
 
     #[test]
     fn test_simple_direct_child() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "child".to_string(),
-            create_transform("root", "child", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "child", Isometry3::translation(1.0, 0.0, 0.0)));
 
-        let result = root_to_child("child", "root", &buffer);
+        let result = root_to_child("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(1.0, 0.0, 0.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -171,19 +511,13 @@ mod tests {
     // Test 2: Intermediate Frames
     #[test]
     fn test_intermediate_frames() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "intermediate".to_string(),
-            create_transform("root", "intermediate", Isometry3::translation(1.0, 1.0, 0.0)),
-        );
-        buffer.insert(
-            "child".to_string(),
-            create_transform("intermediate", "child", Isometry3::translation(1.0, 0.0, 1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "intermediate", Isometry3::translation(1.0, 1.0, 0.0)));
+        buffer.insert(create_transform("intermediate", "child", Isometry3::translation(1.0, 0.0, 1.0)));
 
-        let result = root_to_child("child", "root", &buffer);
+        let result = root_to_child("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(2.0, 1.0, 1.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -192,27 +526,15 @@ mod tests {
     // Test 3: Complex Chain with Multiple Branches
     #[test]
     fn test_complex_chain_with_multiple_branches() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "intermediate1".to_string(),
-            create_transform("root", "intermediate1", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
-        buffer.insert(
-            "intermediate2".to_string(),
-            create_transform("intermediate1", "intermediate2", Isometry3::translation(0.0, 1.0, 0.0)),
-        );
-        buffer.insert(
-            "branch".to_string(),
-            create_transform("intermediate1", "branch", Isometry3::translation(0.0, 0.0, 1.0)),
-        );
-        buffer.insert(
-            "child".to_string(),
-            create_transform("intermediate2", "child", Isometry3::translation(1.0, 1.0, 1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "intermediate1", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("intermediate1", "intermediate2", Isometry3::translation(0.0, 1.0, 0.0)));
+        buffer.insert(create_transform("intermediate1", "branch", Isometry3::translation(0.0, 0.0, 1.0)));
+        buffer.insert(create_transform("intermediate2", "child", Isometry3::translation(1.0, 1.0, 1.0)));
 
-        let result = root_to_child("child", "root", &buffer);
+        let result = root_to_child("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(2.0, 2.0, 1.0);
         assert_eq!(transform.translation, expected_transform.translation);
@@ -222,15 +544,12 @@ mod tests {
 
     #[test]
     fn test_simple_direct_parent() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "child".to_string(),
-            create_transform("root", "child", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "child", Isometry3::translation(1.0, 0.0, 0.0)));
 
-        let result = parent_to_root("child", "root", &buffer);
+        let result = parent_to_root("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-1.0, 0.0, 0.0); // Inverse of the translation
         assert_eq!(transform.translation, expected_transform.translation);
@@ -239,19 +558,13 @@ mod tests {
     // Test 2: Intermediate Frames
     #[test]
     fn test_intermediate_frames_2() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "intermediate".to_string(),
-            create_transform("root", "intermediate", Isometry3::translation(1.0, 1.0, 0.0)),
-        );
-        buffer.insert(
-            "child".to_string(),
-            create_transform("intermediate", "child", Isometry3::translation(1.0, 0.0, 1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "intermediate", Isometry3::translation(1.0, 1.0, 0.0)));
+        buffer.insert(create_transform("intermediate", "child", Isometry3::translation(1.0, 0.0, 1.0)));
 
-        let result = parent_to_root("child", "root", &buffer);
+        let result = parent_to_root("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-2.0, -1.0, -1.0); // Inverse of the combined translation
         assert_eq!(transform.translation, expected_transform.translation);
@@ -260,27 +573,15 @@ mod tests {
     // Test 3: Complex Chain with Multiple Branches
     #[test]
     fn test_complex_chain_with_multiple_branches_2() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "intermediate1".to_string(),
-            create_transform("root", "intermediate1", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
-        buffer.insert(
-            "intermediate2".to_string(),
-            create_transform("intermediate1", "intermediate2", Isometry3::translation(0.0, 1.0, 0.0)),
-        );
-        buffer.insert(
-            "branch".to_string(),
-            create_transform("intermediate1", "branch", Isometry3::translation(0.0, 0.0, 1.0)),
-        );
-        buffer.insert(
-            "child".to_string(),
-            create_transform("intermediate2", "child", Isometry3::translation(1.0, 1.0, 1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "intermediate1", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("intermediate1", "intermediate2", Isometry3::translation(0.0, 1.0, 0.0)));
+        buffer.insert(create_transform("intermediate1", "branch", Isometry3::translation(0.0, 0.0, 1.0)));
+        buffer.insert(create_transform("intermediate2", "child", Isometry3::translation(1.0, 1.0, 1.0)));
 
-        let result = parent_to_root("child", "root", &buffer);
+        let result = parent_to_root("child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         let expected_transform = Isometry3::translation(-2.0, -2.0, -1.0); // Inverse of the chosen path
         assert_eq!(transform.translation, expected_transform.translation);
@@ -288,25 +589,16 @@ mod tests {
 
     #[test]
     fn test_complex_transform_chain() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "frame1".to_string(),
-            create_transform("root", "frame1", Isometry3::translation(1.0, 2.0, 0.0)),
-        );
-        buffer.insert(
-            "frame2".to_string(),
-            create_transform("frame1", "frame2", Isometry3::translation(0.0, 3.0, 1.0)),
-        );
-        buffer.insert(
-            "frame3".to_string(),
-            create_transform("frame2", "frame3", Isometry3::translation(2.0, 0.0, -1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "frame1", Isometry3::translation(1.0, 2.0, 0.0)));
+        buffer.insert(create_transform("frame1", "frame2", Isometry3::translation(0.0, 3.0, 1.0)));
+        buffer.insert(create_transform("frame2", "frame3", Isometry3::translation(2.0, 0.0, -1.0)));
 
-        let buffer = Arc::new(Mutex::new(buffer));
+        let buffer = Arc::new(RwLock::new(buffer));
 
-        let result = lookup_transform("frame1", "frame3", "root", &buffer);
+        let result = lookup_transform("frame1", "frame3", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "frame1");
         assert_eq!(transform.child_frame_id, "frame3");
@@ -319,29 +611,17 @@ mod tests {
     // Test 5: Multiple Intermediate Frames
     #[test]
     fn test_multiple_intermediate_frames() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "frameA".to_string(),
-            create_transform("root", "frameA", Isometry3::translation(1.0, 1.0, 1.0)),
-        );
-        buffer.insert(
-            "frameB".to_string(),
-            create_transform("frameA", "frameB", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
-        buffer.insert(
-            "frameC".to_string(),
-            create_transform("frameB", "frameC", Isometry3::translation(0.0, 2.0, 0.0)),
-        );
-        buffer.insert(
-            "frameD".to_string(),
-            create_transform("frameC", "frameD", Isometry3::translation(0.0, 0.0, 3.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "frameA", Isometry3::translation(1.0, 1.0, 1.0)));
+        buffer.insert(create_transform("frameA", "frameB", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("frameB", "frameC", Isometry3::translation(0.0, 2.0, 0.0)));
+        buffer.insert(create_transform("frameC", "frameD", Isometry3::translation(0.0, 0.0, 3.0)));
 
-        let buffer = Arc::new(Mutex::new(buffer));
+        let buffer = Arc::new(RwLock::new(buffer));
 
-        let result = lookup_transform("root", "frameD", "root", &buffer);
+        let result = lookup_transform("root", "frameD", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "root");
         assert_eq!(transform.child_frame_id, "frameD");
@@ -354,33 +634,22 @@ mod tests {
     // Test 6: Mixed Transformations with Rotations
     #[test]
     fn test_mixed_transformations_with_rotations() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "frame1".to_string(),
-            create_transform("root", "frame1", Isometry3::translation(0.0, 0.0, 1.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "frame1", Isometry3::translation(0.0, 0.0, 1.0)));
 
         let rot = Isometry3::rotation(Vector3::new(0.5, 0.0, 0.0));
         let rot2 = Isometry3::rotation(Vector3::new(0.5, 0.5, 0.0));
 
-        buffer.insert(
-            "frame2".to_string(),
-            create_transform("frame1", "frame2", rot), // Assume rotation around X-axis
-        );
-        buffer.insert(
-            "frame3".to_string(),
-            create_transform("frame2", "frame3", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
-        buffer.insert(
-            "frame4".to_string(),
-            create_transform("frame3", "frame4", rot2),
+        buffer.insert(create_transform("frame1", "frame2", rot), // Assume rotation around X-axis
         );
+        buffer.insert(create_transform("frame2", "frame3", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("frame3", "frame4", rot2));
 
-        let buffer = Arc::new(Mutex::new(buffer));
+        let buffer = Arc::new(RwLock::new(buffer));
 
-        let result = lookup_transform("frame1", "frame4", "root", &buffer);
+        let result = lookup_transform("frame1", "frame4", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "frame1");
         assert_eq!(transform.child_frame_id, "frame4");
@@ -410,6 +679,8 @@ mod tests {
                         )),
                     },
                     json_metadata: "{foo: bar}".to_string(),
+                    time_to_live: None,
+                    frame_kind: FrameKind::Dynamic,
                 },
             ),
             (
@@ -427,6 +698,8 @@ mod tests {
                         )),
                     },
                     json_metadata: "{foo: bar}".to_string(),
+                    time_to_live: None,
+                    frame_kind: FrameKind::Dynamic,
                 },
             ),
             (
@@ -444,6 +717,8 @@ mod tests {
                         )),
                     },
                     json_metadata: "{foo: bar}".to_string(),
+                    time_to_live: None,
+                    frame_kind: FrameKind::Dynamic,
                 },
             ),
             (
@@ -461,12 +736,14 @@ mod tests {
                         )),
                     },
                     json_metadata: "{foo: bar}".to_string(),
+                    time_to_live: None,
+                    frame_kind: FrameKind::Dynamic,
                 },
             ),
         ]);
 
-        let res = parent_to_root("hand", "world", &test_buffer);
-        assert!(!res.is_none());
+        let res = parent_to_root("hand", "world", &FrameTree::from(test_buffer), &TreeConfig::default());
+        assert!(!res.is_err());
         println!("{}", res.unwrap());
         // TODO: verify if this is correct and test
     }
@@ -478,6 +755,8 @@ mod tests {
             child_frame_id: "dummy_1".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
@@ -488,6 +767,8 @@ mod tests {
             child_frame_id: "dummy_2".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
@@ -498,13 +779,15 @@ mod tests {
             child_frame_id: "dummy_3".to_string(),
             transform: Isometry3::default(),
             json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
     #[test]
     fn test_get_frame_children() {
-        let mut buffer = HashMap::<String, TransformStamped>::new();
-        buffer.insert("dummy_1".to_string(), dummy_1_frame());
+        let mut buffer = FrameTree::new();
+        buffer.insert(dummy_1_frame());
 
         //          w
         //          |
@@ -518,7 +801,7 @@ mod tests {
             vec!("dummy_1")
         );
 
-        buffer.insert("dummy_2".to_string(), dummy_2_frame());
+        buffer.insert(dummy_2_frame());
 
         //          w
         //          |
@@ -550,7 +833,7 @@ mod tests {
             Vec::<String>::new()
         );
 
-        buffer.insert("dummy_3".to_string(), dummy_3_frame());
+        buffer.insert(dummy_3_frame());
 
         //          w
         //          |
@@ -579,27 +862,23 @@ mod tests {
             child_frame_id: child_frame_id.to_string(),
             transform,
             json_metadata: "".to_string(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
         }
     }
 
     // Successful Transform Lookup
     #[test]
     fn test_successful_transform_lookup() {
-        let mut buffer = HashMap::new();
-        buffer.insert(
-            "parent".to_string(),
-            create_transform("root", "parent", Isometry3::translation(1.0, 0.0, 0.0)),
-        );
-        buffer.insert(
-            "child".to_string(),
-            create_transform("parent", "child", Isometry3::translation(0.0, 1.0, 0.0)),
-        );
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "parent", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("parent", "child", Isometry3::translation(0.0, 1.0, 0.0)));
 
-        let buffer = Arc::new(Mutex::new(buffer));
+        let buffer = Arc::new(RwLock::new(buffer));
 
-        let result = lookup_transform("parent", "child", "root", &buffer);
+        let result = lookup_transform("parent", "child", "root", &buffer, &TreeConfig::default());
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         let transform = result.unwrap();
         assert_eq!(transform.parent_frame_id, "parent");
         assert_eq!(transform.child_frame_id, "child");
@@ -611,4 +890,523 @@ mod tests {
             expected_transform.translation
         );
     }
+
+    fn add_sample(
+        buffer: &mut HashMap<String, TransformHistory>,
+        frame: TransformStamped,
+    ) {
+        buffer
+            .entry(frame.child_frame_id.clone())
+            .or_insert_with(|| TransformHistory::new(HistoryConfig::default()))
+            .insert(frame);
+    }
+
+    // The samples of these tests are older than the lookups, keep using the newest one
+    fn hold() -> LookupPolicy {
+        LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup_transform_at() {
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        let earlier = now - Duration::from_millis(200);
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: earlier,
+            ..create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: earlier,
+            ..create_transform("robot", "gripper", Isometry3::translation(0.0, 1.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("robot", "gripper", Isometry3::translation(0.0, 2.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("world", "camera", Isometry3::translation(0.0, 0.0, 1.0))
+        });
+
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        let result = lookup_transform_at("world", "gripper", "world", earlier, &hold(), &buffer, &config).unwrap();
+        assert_eq!(result.time_stamp, earlier);
+        assert_eq!(result.transform.translation, Isometry3::translation(1.0, 1.0, 0.0).translation);
+
+        let result = lookup_transform_at("world", "gripper", "world", now, &hold(), &buffer, &config).unwrap();
+        assert_eq!(result.transform.translation, Isometry3::translation(1.0, 2.0, 0.0).translation);
+
+        let result = lookup_transform_at("camera", "gripper", "world", now, &hold(), &buffer, &config).unwrap();
+        assert_eq!(result.transform.translation, Isometry3::translation(1.0, 2.0, -1.0).translation);
+
+        // The camera frame did not exist yet
+        assert!(lookup_transform_at("camera", "gripper", "world", earlier, &hold(), &buffer, &config).is_err());
+    }
+
+    #[test]
+    fn test_lookup_transform_at_interpolates() {
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("world", "conveyor", Isometry3::translation(0.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now + Duration::from_millis(100),
+            ..create_transform("world", "conveyor", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("conveyor", "part", Isometry3::translation(0.0, 1.0, 0.0))
+        });
+
+        let buffer = Arc::new(RwLock::new(buffer));
+        let time = now + Duration::from_millis(40);
+
+        let result = lookup_transform_at("world", "part", "world", time, &hold(), &buffer, &config).unwrap();
+        assert!((result.transform.translation.x - 0.4).abs() < 1e-9);
+        assert_eq!(result.transform.translation.y, 1.0);
+
+        let policy = LookupPolicy {
+            interpolation: InterpolationPolicy::LatestBefore,
+            ..hold()
+        };
+        let result = lookup_transform_at("world", "part", "world", time, &policy, &buffer, &config).unwrap();
+        assert_eq!(result.transform.translation.x, 0.0);
+
+        let policy = LookupPolicy {
+            interpolation: InterpolationPolicy::Exact,
+            ..hold()
+        };
+        assert!(lookup_transform_at("world", "part", "world", time, &policy, &buffer, &config).is_err());
+    }
+
+    #[test]
+    fn test_lookup_transform_at_rejects_extrapolation() {
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now + Duration::from_millis(100),
+            ..create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("robot", "camera", Isometry3::translation(0.0, 1.0, 0.0))
+        });
+
+        let buffer = Arc::new(RwLock::new(buffer));
+        // Stale samples are rejected unless the lookup asks to hold them
+        let err = lookup_transform_at("world", "camera", "world", now + Duration::from_millis(100), &LookupPolicy::default(), &buffer, &config)
+            .unwrap_err();
+        assert!(err.to_string().contains("'camera'"));
+        assert!(err.to_string().contains("100ms"));
+
+        let result = lookup_transform_at("world", "camera", "world", now + Duration::from_millis(100), &hold(), &buffer, &config)
+            .unwrap();
+        assert_eq!(result.transform.translation, Isometry3::translation(1.0, 1.0, 0.0).translation);
+    }
+
+    #[test]
+    fn test_lookup_transform_is_stamped_with_the_oldest_frame() {
+        let now = Instant::now();
+        let mut buffer = FrameTree::new();
+        buffer.insert(TransformStamped {
+            time_stamp: now,
+            ..create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        buffer.insert(TransformStamped {
+            time_stamp: now - Duration::from_millis(500),
+            ..create_transform("robot", "camera", Isometry3::translation(0.0, 1.0, 0.0))
+        });
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        let result = lookup_transform("world", "camera", "world", &buffer, &TreeConfig::default()).unwrap();
+        assert_eq!(result.time_stamp, now - Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_lookup_transform_full() {
+        let config = TreeConfig::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(1000);
+        let mut buffer = HashMap::new();
+
+        // The conveyor moves 1 m along x per second, the camera and the gripper are fixed
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "conveyor", Isometry3::translation(0.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t1,
+            ..create_transform("world", "conveyor", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "camera", Isometry3::translation(0.0, 0.0, 2.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "gripper", Isometry3::translation(1.0, 0.0, 1.0))
+        });
+        // The part was detected by the camera at t0, straight below it
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("camera", "detection", Isometry3::translation(0.0, 0.0, -2.0))
+        });
+
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        // The part rides along with the conveyor, so it should be straight below the gripper at t1
+        let result = lookup_transform_full(
+            "gripper", t1, "detection", t0, "conveyor", "world", &hold(), &buffer,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(result.parent_frame_id, "gripper");
+        assert_eq!(result.child_frame_id, "detection");
+        assert_eq!(result.time_stamp, t1);
+        assert!((result.transform.translation.vector - Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-9);
+
+        // With the world as the fixed frame, the part stays where it was seen
+        let result = lookup_transform_full(
+            "gripper", t1, "detection", t0, "world", "world", &hold(), &buffer,
+            &config,
+        )
+        .unwrap();
+        assert!((result.transform.translation.vector - Vector3::new(-1.0, 0.0, -1.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_lookup_transform_at_planned_time() {
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("robot", "tcp", Isometry3::translation(0.0, 0.0, 1.0))
+        });
+        buffer.get_mut("tcp").unwrap().set_plan(
+            (1..=4)
+                .map(|i| TransformStamped {
+                    time_stamp: now + Duration::from_secs(i),
+                    ..create_transform("robot", "tcp", Isometry3::translation(i as f64, 0.0, 1.0))
+                })
+                .collect(),
+        );
+
+        let buffer = Arc::new(RwLock::new(buffer));
+        let future = now + Duration::from_millis(2500);
+
+        // The robot base is not planned, so it holds while the tcp follows its plan
+        let result = lookup_transform_at("world", "tcp", "world", future, &hold(), &buffer, &config).unwrap();
+        assert_eq!(result.time_stamp, future);
+        assert!((result.transform.translation.vector - Vector3::new(3.5, 0.0, 1.0)).norm() < 1e-9);
+
+        let policy = LookupPolicy {
+            source: TimelineSource::Observed,
+            ..hold()
+        };
+        let result = lookup_transform_at("world", "tcp", "world", future, &policy, &buffer, &config).unwrap();
+        assert!((result.transform.translation.vector - Vector3::new(1.0, 0.0, 1.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_lookup_transform_at_across_attach() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "table", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "gripper", Isometry3::translation(1.0, 0.0, 1.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0 + ms(100),
+            ..create_transform("world", "gripper", Isometry3::translation(1.0, 0.0, 0.5))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0 + ms(200),
+            ..create_transform("world", "gripper", Isometry3::translation(3.0, 0.0, 1.0))
+        });
+        // The part lies on the table until it is picked at 100 ms
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("table", "part", Isometry3::translation(0.0, 0.0, 0.5))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0 + ms(100),
+            ..create_transform("gripper", "part", Isometry3::translation(0.0, 0.0, 0.0))
+        });
+
+        let buffer = Arc::new(RwLock::new(buffer));
+        let at = |ms_since| {
+            lookup_transform_at("world", "part", "world", t0 + ms(ms_since), &hold(), &buffer, &TreeConfig::default())
+                .unwrap()
+                .transform
+                .translation
+                .vector
+        };
+
+        assert!((at(50) - Vector3::new(1.0, 0.0, 0.5)).norm() < 1e-9);
+        assert!((at(100) - Vector3::new(1.0, 0.0, 0.5)).norm() < 1e-9);
+        assert!((at(150) - Vector3::new(2.0, 0.0, 0.75)).norm() < 1e-9);
+        assert!((at(200) - Vector3::new(3.0, 0.0, 1.0)).norm() < 1e-9);
+
+        // The topology of the tree at a time follows the parents valid at that time
+        let snapshot = snapshot_at(&buffer.read().unwrap(), t0 + ms(50));
+        assert_eq!(snapshot.get("part").unwrap().parent_frame_id, "table");
+        let snapshot = snapshot_at(&buffer.read().unwrap(), t0 + ms(150));
+        assert_eq!(snapshot.get("part").unwrap().parent_frame_id, "gripper");
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        let config = TreeConfig::default();
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("rig", "camera", Isometry3::translation(0.0, 1.0, 0.0)));
+        buffer.insert(create_transform("c", "a", Isometry3::identity()));
+        buffer.insert(create_transform("a", "b", Isometry3::identity()));
+        buffer.insert(create_transform("b", "c", Isometry3::identity()));
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        assert_eq!(
+            lookup_transform("robot", "gripper", "world", &buffer, &config),
+            Err(LookupError::UnknownSourceFrame("gripper".to_string()))
+        );
+        assert_eq!(
+            lookup_transform("table", "robot", "world", &buffer, &config),
+            Err(LookupError::UnknownTargetFrame("table".to_string()))
+        );
+        assert_eq!(
+            lookup_transform("robot", "camera", "world", &buffer, &config),
+            Err(LookupError::DisconnectedFrames {
+                source: "camera".to_string(),
+                target: "robot".to_string()
+            })
+        );
+        assert_eq!(
+            lookup_transform("camera", "robot", "world", &buffer, &config),
+            Err(LookupError::DisconnectedFrames {
+                source: "robot".to_string(),
+                target: "camera".to_string()
+            })
+        );
+        for (parent, child) in [("robot", "b"), ("b", "robot")] {
+            match lookup_transform(parent, child, "world", &buffer, &config) {
+                Err(LookupError::CycleDetected(mut cycle)) => {
+                    cycle.sort();
+                    assert_eq!(cycle, vec!("a", "b", "c"));
+                }
+                other => panic!("Expected a cycle, got {:?}", other),
+            }
+        }
+
+        // Cycles elsewhere in the buffer don't affect the lookup
+        assert!(lookup_transform("world", "robot", "world", &buffer, &config).is_ok());
+        assert!(lookup_transform("world", "world", "world", &buffer, &config).is_ok());
+    }
+
+    #[test]
+    fn test_lookup_chain_too_long() {
+        let mut buffer = FrameTree::new();
+        let config = TreeConfig {
+            max_chain_length: 10,
+            ..TreeConfig::default()
+        };
+        for i in 0..config.max_chain_length + 1 {
+            let parent = match i {
+                0 => "world".to_string(),
+                _ => format!("frame_{}", i - 1),
+            };
+            let child = format!("frame_{}", i);
+            buffer.insert(create_transform(&parent, &child, Isometry3::identity()));
+        }
+        let last = format!("frame_{}", config.max_chain_length);
+        assert_eq!(
+            parent_to_root(&last, "world", &buffer, &config),
+            Err(LookupError::ChainTooLong {
+                frame: last.clone(),
+                limit: config.max_chain_length
+            })
+        );
+        // Another tree can allow longer chains
+        assert!(parent_to_root(&last, "world", &buffer, &TreeConfig::default()).is_ok());
+        let previous = format!("frame_{}", config.max_chain_length - 1);
+        assert!(parent_to_root(&previous, "world", &buffer, &config).is_ok());
+    }
+
+    #[test]
+    fn test_lookup_transform_at_errors() {
+        let config = TreeConfig::default();
+        let now = Instant::now();
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("world", "robot", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("rig", "camera", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        let buffer = Arc::new(RwLock::new(buffer));
+        let policy = LookupPolicy::default();
+
+        assert_eq!(
+            lookup_transform_at("world", "gripper", "world", now, &policy, &buffer, &config),
+            Err(LookupError::UnknownSourceFrame("gripper".to_string()))
+        );
+        assert_eq!(
+            lookup_transform_at("table", "robot", "world", now, &policy, &buffer, &config),
+            Err(LookupError::UnknownTargetFrame("table".to_string()))
+        );
+        assert_eq!(
+            lookup_transform_at("world", "camera", "world", now, &policy, &buffer, &config),
+            Err(LookupError::DisconnectedFrames {
+                source: "camera".to_string(),
+                target: "world".to_string()
+            })
+        );
+        assert_eq!(
+            lookup_transform_at("world", "robot", "world", now - Duration::from_millis(10), &policy, &buffer, &config),
+            Err(LookupError::TimeOutOfRange {
+                frame: "robot".to_string(),
+                gap: Duration::from_millis(10),
+                range: OutOfRange::Past
+            })
+        );
+    }
+
+    #[test]
+    fn test_lookup_transform_lca() {
+        let config = TreeConfig::default();
+        let mut buffer = FrameTree::new();
+        buffer.insert(create_transform("root", "frame1", Isometry3::translation(1.0, 2.0, 0.0)));
+        buffer.insert(create_transform("frame1", "frame2", Isometry3::translation(0.0, 3.0, 1.0)));
+        buffer.insert(create_transform(
+            "frame2",
+            "frame3",
+            Isometry3::new(Vector3::new(2.0, 0.0, -1.0), Vector3::z() * 0.3),
+        ));
+        buffer.insert(create_transform(
+            "frame1",
+            "branch",
+            Isometry3::new(Vector3::new(0.0, 0.0, 1.0), Vector3::x() * 0.5),
+        ));
+        // A floating subtree that has no connection to the root
+        buffer.insert(create_transform("rig", "camera", Isometry3::translation(0.0, 0.0, 2.0)));
+        buffer.insert(create_transform("camera", "lens", Isometry3::translation(0.0, 0.0, 0.1)));
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        for (parent, child) in [
+            ("frame1", "frame3"),
+            ("frame3", "frame1"),
+            ("branch", "frame3"),
+            ("frame3", "branch"),
+            ("root", "frame3"),
+            ("frame2", "frame2"),
+        ] {
+            let lca = lookup_transform_lca(parent, child, &buffer, &config).unwrap();
+            let rooted = lookup_transform(parent, child, "root", &buffer, &config).unwrap();
+            assert!((lca.transform.translation.vector - rooted.transform.translation.vector).norm() < 1e-9);
+            assert!(lca.transform.rotation.angle_to(&rooted.transform.rotation) < 1e-9);
+        }
+
+        let result = lookup_transform_lca("rig", "lens", &buffer, &config).unwrap();
+        assert_eq!(result.transform.translation.vector, Vector3::new(0.0, 0.0, 2.1));
+        let result = lookup_transform_lca("lens", "camera", &buffer, &config).unwrap();
+        assert_eq!(result.transform.translation.vector, Vector3::new(0.0, 0.0, -0.1));
+
+        assert_eq!(
+            lookup_transform_lca("frame1", "lens", &buffer, &config),
+            Err(LookupError::DisconnectedFrames {
+                source: "lens".to_string(),
+                target: "frame1".to_string()
+            })
+        );
+        assert_eq!(
+            lookup_transform_lca("frame1", "nothing", &buffer, &config),
+            Err(LookupError::UnknownSourceFrame("nothing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_lookup_transform_lca_at() {
+        let now = Instant::now();
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("rig", "camera", Isometry3::translation(0.0, 0.0, 1.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now + Duration::from_millis(100),
+            ..create_transform("rig", "camera", Isometry3::translation(0.0, 0.0, 2.0))
+        });
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: now,
+            ..create_transform("rig", "light", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        let buffer = Arc::new(RwLock::new(buffer));
+
+        let result = lookup_transform_lca_at(
+            "light", "camera", now + Duration::from_millis(50), &hold(), &buffer,
+            &TreeConfig::default(),
+        )
+        .unwrap();
+        assert!((result.transform.translation.vector - Vector3::new(-1.0, 0.0, 1.5)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_lookup_transform_lca_at_ignores_hops_above() {
+        let config = TreeConfig::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(100);
+        let mut buffer = HashMap::new();
+        add_sample(&mut buffer, TransformStamped {
+            time_stamp: t0,
+            ..create_transform("world", "rig", Isometry3::translation(1.0, 0.0, 0.0))
+        });
+        for time_stamp in [t0, t1] {
+            add_sample(&mut buffer, TransformStamped {
+                time_stamp,
+                ..create_transform("rig", "camera", Isometry3::translation(0.0, 1.0, 0.0))
+            });
+            add_sample(&mut buffer, TransformStamped {
+                time_stamp,
+                ..create_transform("camera", "lens", Isometry3::translation(0.0, 0.0, 0.1))
+            });
+        }
+        let buffer = Arc::new(RwLock::new(buffer));
+        let policy = LookupPolicy::default();
+
+        // The hop from the rig to the world is stale, but it is above both frames
+        let result = lookup_transform_lca_at("camera", "lens", t1, &policy, &buffer, &config).unwrap();
+        assert!((result.transform.translation.z - 0.1).abs() < 1e-9);
+        let result = lookup_transform_lca_at("lens", "rig", t1, &policy, &buffer, &config).unwrap();
+        assert!((result.transform.translation.y + 1.0).abs() < 1e-9);
+        assert!(matches!(
+            lookup_transform_lca_at("world", "lens", t1, &policy, &buffer, &config),
+            Err(LookupError::TimeOutOfRange { frame, .. }) if frame == "rig"
+        ));
+
+        assert_eq!(
+            lookup_transform_lca_at("ghost", "ghost", t1, &policy, &buffer, &config),
+            Err(LookupError::UnknownSourceFrame("ghost".to_string()))
+        );
+        assert!(lookup_transform_lca_at("rig", "rig", t1, &policy, &buffer, &config).is_ok());
+    }
 }
@@ -0,0 +1,477 @@
+use crate::*;
+use nalgebra::Isometry3;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::time::Instant;
+
+// Attach the frame to a new parent. If keep_world_pose is set, the transform is
+// recomputed so that the frame stays where it is, otherwise the old transform is
+// kept and is now relative to the new parent.
+pub fn reparent_frame(
+    frame_id: &str,
+    new_parent_frame_id: &str,
+    keep_world_pose: bool,
+    root_frame_id: &str,
+    buffer: &Arc<RwLock<FrameTree>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, ErrorMsg> {
+    let mut buffer_local = buffer.write().unwrap();
+    let frame = match buffer_local.get(frame_id) {
+        Some(frame) => frame.clone(),
+        None => {
+            return Err(ErrorMsg::new(&format!(
+                "Frame '{}' does not exist in the buffer.",
+                frame_id
+            )))
+        }
+    };
+
+    let transform = match keep_world_pose {
+        true => pose_in_new_parent(frame_id, new_parent_frame_id, root_frame_id, &buffer_local, config)?,
+        false => frame.transform,
+    };
+
+    let reparented = TransformStamped {
+        time_stamp: Instant::now(),
+        parent_frame_id: new_parent_frame_id.to_string(),
+        transform,
+        ..frame
+    };
+    check_reparent(&reparented, &buffer_local, config)?;
+
+    buffer_local.insert(reparented.clone());
+    log::info!(
+        "Frame '{}' reparented to frame '{}'",
+        frame_id,
+        new_parent_frame_id
+    );
+    Ok(reparented)
+}
+
+// Same as reparent_frame, but the frame is attached to the new parent starting from the
+// requested time, so that lookups before that time still follow the old parent.
+// The change is just another sample of the frame: a broadcaster that keeps publishing the
+// frame relative to the old parent takes it back with its next sample, so that broadcaster
+// has to be stopped or switched to the new parent first.
+#[allow(clippy::too_many_arguments)]
+pub fn reparent_frame_at(
+    frame_id: &str,
+    new_parent_frame_id: &str,
+    keep_world_pose: bool,
+    root_frame_id: &str,
+    time: Instant,
+    policy: &LookupPolicy,
+    buffer: &Arc<RwLock<HashMap<String, TransformHistory>>>,
+    config: &TreeConfig,
+) -> Result<TransformStamped, ErrorMsg> {
+    let mut buffer_local = buffer.write().unwrap();
+    let frame = match buffer_local.get(frame_id) {
+        Some(history) => history.resolve_at(time, policy)?,
+        None => {
+            return Err(ErrorMsg::new(&format!(
+                "Frame '{}' does not exist in the buffer.",
+                frame_id
+            )))
+        }
+    };
+
+    let transform = match keep_world_pose {
+        true => {
+            let new_parent =
+                frame_to_root_at(new_parent_frame_id, root_frame_id, time, policy, &buffer_local, config)?;
+            let child = frame_to_root_at(frame_id, root_frame_id, time, policy, &buffer_local, config)?;
+            new_parent.inverse() * child
+        }
+        false => frame.transform,
+    };
+
+    let reparented = TransformStamped {
+        time_stamp: time,
+        parent_frame_id: new_parent_frame_id.to_string(),
+        transform,
+        ..frame
+    };
+    check_reparent(&reparented, &snapshot_at(&buffer_local, time), config)?;
+
+    if let Some(history) = buffer_local.get_mut(frame_id) {
+        history.insert(reparented.clone());
+    }
+    log::info!(
+        "Frame '{}' reparented to frame '{}'",
+        frame_id,
+        new_parent_frame_id
+    );
+    Ok(reparented)
+}
+
+// Remove the frame from the buffer, its children are either removed as well or
+// adopted by the parent of the removed frame, see RemovalMode
+pub fn remove_frame(
+    frame_id: &str,
+    mode: RemovalMode,
+    buffer: &Arc<RwLock<FrameTree>>,
+) -> Result<RemovalReport, ErrorMsg> {
+    let mut buffer_local = buffer.write().unwrap();
+    let frame = match buffer_local.remove(frame_id) {
+        Some(frame) => frame,
+        None => {
+            return Err(ErrorMsg::new(&format!(
+                "Frame '{}' does not exist in the buffer.",
+                frame_id
+            )))
+        }
+    };
+
+    let mut report = RemovalReport::default();
+    match mode {
+        RemovalMode::Cascade => {
+            let mut stack = vec![frame.child_frame_id.clone()];
+            while let Some(current_frame) = stack.pop() {
+                for (child_id, _) in get_frame_children(&current_frame, &buffer_local) {
+                    if let Some(child) = buffer_local.remove(&child_id) {
+                        stack.push(child_id);
+                        report.removed.push(child);
+                    }
+                }
+            }
+        }
+        RemovalMode::AdoptChildren => {
+            // The children keep their time stamps, being adopted doesn't make them any fresher
+            for (_, child) in get_frame_children(&frame.child_frame_id, &buffer_local) {
+                let adopted = TransformStamped {
+                    parent_frame_id: frame.parent_frame_id.clone(),
+                    transform: frame.transform * child.transform,
+                    ..child
+                };
+                buffer_local.insert(adopted.clone());
+                report.reparented.push(adopted);
+            }
+        }
+    }
+    report.removed.insert(0, frame);
+
+    log::info!(
+        "Frame '{}' removed, {} frames removed and {} frames reparented in total",
+        frame_id,
+        report.removed.len(),
+        report.reparented.len()
+    );
+    Ok(report)
+}
+
+// Rename the frame and point all of its children to the new name in one step
+pub fn rename_frame(
+    frame_id: &str,
+    new_frame_id: &str,
+    buffer: &Arc<RwLock<FrameTree>>,
+    names: &ReservedNames,
+) -> Result<TransformStamped, ErrorMsg> {
+    let mut buffer_local = buffer.write().unwrap();
+    if names.is_reserved(new_frame_id) {
+        return Err(InsertError::ReservedName(new_frame_id.to_string()).into());
+    }
+    // A frame that only appears as a parent exists too, renaming a child to it would parent it to itself
+    if buffer_local.contains_frame(new_frame_id) {
+        return Err(ErrorMsg::new(&format!(
+            "Can't rename frame '{}', frame '{}' already exists.",
+            frame_id, new_frame_id
+        )));
+    }
+    let frame = match buffer_local.remove(frame_id) {
+        Some(frame) => frame,
+        None => {
+            return Err(ErrorMsg::new(&format!(
+                "Frame '{}' does not exist in the buffer.",
+                frame_id
+            )))
+        }
+    };
+
+    let renamed = TransformStamped {
+        child_frame_id: new_frame_id.to_string(),
+        ..frame
+    };
+    buffer_local.insert(renamed.clone());
+    for (_, child) in get_frame_children(frame_id, &buffer_local) {
+        buffer_local.insert(TransformStamped {
+            parent_frame_id: new_frame_id.to_string(),
+            ..child
+        });
+    }
+
+    log::info!("Frame '{}' renamed to '{}'", frame_id, new_frame_id);
+    Ok(renamed)
+}
+
+fn pose_in_new_parent(
+    frame_id: &str,
+    new_parent_frame_id: &str,
+    root_frame_id: &str,
+    buffer: &FrameTree,
+    config: &TreeConfig,
+) -> Result<Isometry3<f64>, ErrorMsg> {
+    let up_chain = parent_to_root(new_parent_frame_id, root_frame_id, buffer, config)?;
+    let down_chain = root_to_child(frame_id, root_frame_id, buffer, config)?;
+    Ok(up_chain * down_chain)
+}
+
+// The chain through the frame and the frames below it must stay within the limit, like for inserts
+fn check_reparent(
+    frame: &TransformStamped,
+    buffer: &FrameTree,
+    config: &TreeConfig,
+) -> Result<(), ErrorMsg> {
+    if frame.parent_frame_id == frame.child_frame_id {
+        Err(ErrorMsg::new(&format!(
+            "Frame '{}' can't be its own parent.",
+            frame.child_frame_id
+        )))
+    } else if check_would_produce_cycle(frame, buffer) {
+        Err(ErrorMsg::new(&format!(
+            "Reparenting frame '{}' to frame '{}' would produce a cycle.",
+            frame.child_frame_id, frame.parent_frame_id
+        )))
+    } else {
+        Ok(check_placement(frame, buffer, config)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::{Isometry3, Vector3};
+    use std::collections::HashMap;
+    use std::f64::consts::FRAC_PI_2;
+    use std::sync::{Arc, RwLock};
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    fn create_transform(
+        parent_frame_id: &str,
+        child_frame_id: &str,
+        transform: Isometry3<f64>,
+    ) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent_frame_id.to_string(),
+            child_frame_id: child_frame_id.to_string(),
+            transform,
+            json_metadata: "".to_string(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    fn cell() -> FrameTree {
+        FrameTree::from_iter(vec![
+            create_transform("world", "table", Isometry3::translation(1.0, 0.0, 0.0)),
+            create_transform(
+                "world",
+                "gripper",
+                Isometry3::new(Vector3::new(1.0, 0.0, 1.0), Vector3::z() * FRAC_PI_2),
+            ),
+            create_transform("table", "part", Isometry3::translation(0.0, 1.0, 0.0)),
+        ])
+    }
+
+    #[test]
+    fn test_reparent_frame_keeps_world_pose() {
+        let config = TreeConfig::default();
+        let buffer = Arc::new(RwLock::new(cell()));
+        let before = lookup_transform("world", "part", "world", &buffer, &config).unwrap();
+
+        let reparented = reparent_frame("part", "gripper", true, "world", &buffer, &config).unwrap();
+        assert_eq!(reparented.parent_frame_id, "gripper");
+
+        let after = lookup_transform("world", "part", "world", &buffer, &config).unwrap();
+        assert!((after.transform.translation.vector - before.transform.translation.vector).norm() < 1e-9);
+        assert!(after.transform.rotation.angle_to(&before.transform.rotation) < 1e-9);
+    }
+
+    #[test]
+    fn test_reparent_frame_keeps_local_transform() {
+        let config = TreeConfig::default();
+        let buffer = Arc::new(RwLock::new(cell()));
+
+        let reparented = reparent_frame("part", "gripper", false, "world", &buffer, &config).unwrap();
+        assert_eq!(reparented.transform, Isometry3::translation(0.0, 1.0, 0.0));
+
+        let after = lookup_transform("world", "part", "world", &buffer, &config).unwrap();
+        assert!((after.transform.translation.vector - Vector3::new(0.0, 0.0, 1.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_reparent_frame_rejects_cycles() {
+        let config = TreeConfig::default();
+        let buffer = Arc::new(RwLock::new(cell()));
+        assert!(reparent_frame("table", "part", true, "world", &buffer, &config).is_err());
+        assert!(reparent_frame("table", "table", true, "world", &buffer, &config).is_err());
+        assert!(reparent_frame("nothing", "table", true, "world", &buffer, &config).is_err());
+        assert_eq!(buffer.read().unwrap().get("table").unwrap().parent_frame_id, "world");
+    }
+
+    #[test]
+    fn test_reparent_frame_respects_chain_length() {
+        let config = TreeConfig {
+            max_chain_length: 2,
+            ..TreeConfig::default()
+        };
+        let buffer = Arc::new(RwLock::new(cell()));
+        // The gripper would be three frames below the world, and so would the part below the table
+        assert!(reparent_frame("gripper", "part", false, "world", &buffer, &config).is_err());
+        assert!(reparent_frame("table", "gripper", false, "world", &buffer, &config).is_err());
+        assert!(reparent_frame("part", "gripper", false, "world", &buffer, &config).is_ok());
+    }
+
+    #[test]
+    fn test_reparent_frame_at() {
+        let config = TreeConfig::default();
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(100);
+        let mut history_buffer = HashMap::new();
+        for frame in cell().into_frames().into_values() {
+            let mut history = TransformHistory::new(HistoryConfig::default());
+            history.insert(TransformStamped {
+                time_stamp: t0,
+                ..frame
+            });
+            history_buffer.insert(history.latest().unwrap().child_frame_id.clone(), history);
+        }
+        let buffer = Arc::new(RwLock::new(history_buffer));
+        let policy = LookupPolicy {
+            extrapolation: ExtrapolationPolicy::Hold,
+            ..Default::default()
+        };
+
+        reparent_frame_at("part", "gripper", true, "world", t1, &policy, &buffer, &config).unwrap();
+
+        let buffer_local = buffer.read().unwrap();
+        assert_eq!(buffer_local.get("part").unwrap().parent_at(t0), Some("table"));
+        assert_eq!(buffer_local.get("part").unwrap().parent_at(t1), Some("gripper"));
+        drop(buffer_local);
+
+        let before = lookup_transform_at("world", "part", "world", t0, &policy, &buffer, &config).unwrap();
+        let after = lookup_transform_at("world", "part", "world", t1, &policy, &buffer, &config).unwrap();
+        assert!((after.transform.translation.vector - before.transform.translation.vector).norm() < 1e-9);
+
+        // The next sample of a broadcaster still publishing the old parent takes the frame back
+        let t2 = t1 + Duration::from_millis(100);
+        let part = cell().get("part").unwrap().clone();
+        buffer.write().unwrap().get_mut("part").unwrap().insert(TransformStamped {
+            time_stamp: t2,
+            ..part
+        });
+        let buffer_local = buffer.read().unwrap();
+        assert_eq!(buffer_local.get("part").unwrap().parent_at(t1), Some("gripper"));
+        assert_eq!(buffer_local.get("part").unwrap().parent_at(t2), Some("table"));
+    }
+
+    fn cell_with_fixture() -> FrameTree {
+        let mut buffer = cell();
+        buffer.insert(create_transform(
+            "table",
+            "fixture",
+            Isometry3::new(Vector3::new(0.0, 0.0, 0.5), Vector3::z() * FRAC_PI_2),
+        ));
+        buffer.insert(create_transform("fixture", "clamp", Isometry3::translation(1.0, 0.0, 0.0)));
+        buffer.insert(create_transform("clamp", "jaw", Isometry3::translation(0.0, 0.0, 0.1)));
+        buffer.insert(create_transform("fixture", "pin", Isometry3::translation(0.0, 1.0, 0.0)));
+        buffer
+    }
+
+    #[test]
+    fn test_remove_frame_cascade() {
+        let buffer = Arc::new(RwLock::new(cell_with_fixture()));
+
+        let report = remove_frame("fixture", RemovalMode::Cascade, &buffer).unwrap();
+        let mut removed = report
+            .removed
+            .iter()
+            .map(|frame| frame.child_frame_id.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(removed[0], "fixture");
+        removed.sort();
+        assert_eq!(removed, vec!("clamp", "fixture", "jaw", "pin"));
+        assert!(report.reparented.is_empty());
+
+        let mut remaining = buffer.read().unwrap().keys().cloned().collect::<Vec<String>>();
+        remaining.sort();
+        assert_eq!(remaining, vec!("gripper", "part", "table"));
+    }
+
+    #[test]
+    fn test_remove_frame_adopt_children() {
+        let config = TreeConfig::default();
+        let buffer = Arc::new(RwLock::new(cell_with_fixture()));
+        let clamp_before = lookup_transform("world", "clamp", "world", &buffer, &config).unwrap();
+        let jaw_before = lookup_transform("world", "jaw", "world", &buffer, &config).unwrap();
+        let clamp_stamp = buffer.read().unwrap().get("clamp").unwrap().time_stamp;
+
+        let report = remove_frame("fixture", RemovalMode::AdoptChildren, &buffer).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.removed[0].child_frame_id, "fixture");
+        let mut reparented = report
+            .reparented
+            .iter()
+            .map(|frame| (frame.child_frame_id.clone(), frame.parent_frame_id.clone()))
+            .collect::<Vec<(String, String)>>();
+        reparented.sort();
+        assert_eq!(
+            reparented,
+            vec!(
+                ("clamp".to_string(), "table".to_string()),
+                ("pin".to_string(), "table".to_string())
+            )
+        );
+
+        let clamp_after = lookup_transform("world", "clamp", "world", &buffer, &config).unwrap();
+        let jaw_after = lookup_transform("world", "jaw", "world", &buffer, &config).unwrap();
+        assert!((clamp_after.transform.translation.vector - clamp_before.transform.translation.vector).norm() < 1e-9);
+        assert!((jaw_after.transform.translation.vector - jaw_before.transform.translation.vector).norm() < 1e-9);
+        assert!(jaw_after.transform.rotation.angle_to(&jaw_before.transform.rotation) < 1e-9);
+        // Adopted frames go stale as if nothing happened
+        assert_eq!(buffer.read().unwrap().get("clamp").unwrap().time_stamp, clamp_stamp);
+        assert!(remove_frame("fixture", RemovalMode::Cascade, &buffer).is_err());
+    }
+
+    #[test]
+    fn test_rename_frame() {
+        let config = TreeConfig::default();
+        let buffer = Arc::new(RwLock::new(cell_with_fixture()));
+        let pin_before = lookup_transform("world", "pin", "world", &buffer, &config).unwrap();
+
+        let renamed = rename_frame("fixture", "fixture_a", &buffer, &ReservedNames::default()).unwrap();
+        assert_eq!(renamed.child_frame_id, "fixture_a");
+        assert_eq!(renamed.parent_frame_id, "table");
+
+        let buffer_local = buffer.read().unwrap().clone();
+        assert!(!buffer_local.contains_key("fixture"));
+        assert_eq!(buffer_local.get("fixture_a").unwrap().child_frame_id, "fixture_a");
+        assert_eq!(buffer_local.get("clamp").unwrap().parent_frame_id, "fixture_a");
+        assert_eq!(buffer_local.get("pin").unwrap().parent_frame_id, "fixture_a");
+        assert!(get_frame_children("fixture", &buffer_local).is_empty());
+
+        let pin_after = lookup_transform("world", "pin", "world", &buffer, &config).unwrap();
+        assert_eq!(pin_after.transform, pin_before.transform);
+    }
+
+    #[test]
+    fn test_rename_frame_rejects_collisions() {
+        let buffer = Arc::new(RwLock::new(cell_with_fixture()));
+        assert!(rename_frame("fixture", "table", &buffer, &ReservedNames::default()).is_err());
+        assert!(rename_frame("fixture", "world", &buffer, &ReservedNames::default()).is_err());
+        assert!(rename_frame("nothing", "something", &buffer, &ReservedNames::default()).is_err());
+
+        // The rig is only known as the parent of the camera
+        buffer.write().unwrap().insert(create_transform("rig", "camera", Isometry3::identity()));
+        assert!(rename_frame("camera", "rig", &buffer, &ReservedNames::default()).is_err());
+        assert_eq!(buffer.read().unwrap().get("camera").unwrap().parent_frame_id, "rig");
+        buffer.write().unwrap().remove("camera");
+
+        let buffer_local = buffer.read().unwrap();
+        assert!(buffer_local.contains_key("fixture"));
+        assert_eq!(buffer_local.get("clamp").unwrap().parent_frame_id, "fixture");
+        assert_eq!(buffer_local.len(), cell_with_fixture().len());
+    }
+}
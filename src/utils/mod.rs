@@ -1,4 +1,6 @@
 pub mod manipulation;
 pub mod lookup;
 pub mod cycles;
-pub mod treeviz;
\ No newline at end of file
+pub mod forest;
+pub mod treeviz;
+pub mod redis;
\ No newline at end of file
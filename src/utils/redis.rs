@@ -0,0 +1,335 @@
+use crate::*;
+use redis::Commands;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, Instant};
+
+// Frames shared through Redis. Every frame is a hash at '<namespace>:frame:<child_frame_id>'
+// and its history is a list of encoded samples at '<namespace>:history:<child_frame_id>'.
+pub struct RedisStorage {
+    client: redis::Client,
+    connection: Mutex<redis::Connection>,
+    namespace: String,
+    // Written with every frame, so that a tree can ignore the notifications about its own changes
+    origin: String,
+    config: HistoryConfig,
+}
+
+impl RedisStorage {
+    pub fn new(url: &str, namespace: &str, config: HistoryConfig) -> Result<RedisStorage, ErrorMsg> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection()?;
+        Ok(RedisStorage {
+            client,
+            connection: Mutex::new(connection),
+            namespace: namespace.to_string(),
+            origin: format!("{:016x}", rand::random::<u64>()),
+            config,
+        })
+    }
+
+    pub fn frame_key(&self, frame_id: &str) -> String {
+        format!("{}:frame:{}", self.namespace, frame_id)
+    }
+
+    fn history_key(&self, frame_id: &str) -> String {
+        format!("{}:history:{}", self.namespace, frame_id)
+    }
+
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+}
+
+impl fmt::Debug for RedisStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedisStorage")
+            .field("address", &self.client.get_connection_info().addr)
+            .field("namespace", &self.namespace)
+            .field("origin", &self.origin)
+            .finish()
+    }
+}
+
+impl FrameStorage for RedisStorage {
+    fn get(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+        let fields: HashMap<String, String> =
+            self.connection.lock().unwrap().hgetall(self.frame_key(frame_id))?;
+        match fields.is_empty() {
+            true => Ok(None),
+            false => decode_frame(&fields).map(Some),
+        }
+    }
+
+    fn put(&self, frame: &TransformStamped) -> Result<(), ErrorMsg> {
+        let fields = encode_frame(frame, &self.origin);
+        let sample = serde_json::to_string(&fields.iter().cloned().collect::<HashMap<String, String>>())
+            .map_err(|e| ErrorMsg::new(&format!("Encoding frame '{}' failed with: '{}'.", frame.child_frame_id, e)))?;
+        let frame_key = self.frame_key(&frame.child_frame_id);
+        let history_key = self.history_key(&frame.child_frame_id);
+        // The frame is updated in place instead of being deleted and written again,
+        // so that other trees never see it disappear
+        redis::pipe()
+            .atomic()
+            .hdel(&frame_key, "time_to_live")
+            .ignore()
+            .hset_multiple(&frame_key, &fields)
+            .ignore()
+            .rpush(&history_key, sample)
+            .ignore()
+            .ltrim(&history_key, -(self.config.max_samples as isize), -1)
+            .ignore()
+            .query::<()>(&mut *self.connection.lock().unwrap())?;
+        Ok(())
+    }
+
+    fn remove(&self, frame_id: &str) -> Result<Option<TransformStamped>, ErrorMsg> {
+        let frame = self.get(frame_id)?;
+        redis::pipe()
+            .atomic()
+            .del(self.frame_key(frame_id))
+            .ignore()
+            .del(self.history_key(frame_id))
+            .ignore()
+            .query::<()>(&mut *self.connection.lock().unwrap())?;
+        Ok(frame)
+    }
+
+    fn frames(&self) -> Result<Vec<TransformStamped>, ErrorMsg> {
+        let mut connection = self.connection.lock().unwrap();
+        let keys = connection
+            .scan_match::<_, String>(self.frame_key("*"))?
+            .collect::<Vec<String>>();
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut pipe = redis::pipe();
+        keys.iter().for_each(|key| {
+            pipe.hgetall(key);
+        });
+        let frames: Vec<HashMap<String, String>> = pipe.query(&mut *connection)?;
+        frames
+            .iter()
+            // Removed after the scan
+            .filter(|fields| !fields.is_empty())
+            .map(decode_frame)
+            .collect()
+    }
+
+    fn history(&self, frame_id: &str) -> Result<Vec<TransformStamped>, ErrorMsg> {
+        let samples: Vec<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .lrange(self.history_key(frame_id), 0, -1)?;
+        samples
+            .iter()
+            .map(|sample| {
+                serde_json::from_str::<HashMap<String, String>>(sample)
+                    .map_err(|e| ErrorMsg::new(&format!("Decoding a sample of frame '{}' failed with: '{}'.", frame_id, e)))
+                    .and_then(|fields| decode_frame(&fields))
+            })
+            .collect()
+    }
+}
+
+// The fields of the hash that stores the frame, the time stamp is unix time in microseconds
+// and the time to live is in milliseconds, it is left out for frames that don't expire
+pub fn encode_frame(frame: &TransformStamped, origin: &str) -> Vec<(String, String)> {
+    let transform = serde_json::to_string(&isometry_to_json_transform(&frame.transform)).unwrap_or_default();
+    let frame_kind = serde_json::to_value(frame.frame_kind)
+        .ok()
+        .and_then(|kind| kind.as_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut fields = vec![
+        ("parent_frame_id".to_string(), frame.parent_frame_id.clone()),
+        ("child_frame_id".to_string(), frame.child_frame_id.clone()),
+        ("time_stamp".to_string(), instant_to_unix_micros(frame.time_stamp).to_string()),
+        ("transform".to_string(), transform),
+        ("json_metadata".to_string(), frame.json_metadata.clone()),
+        ("frame_kind".to_string(), frame_kind),
+        ("origin".to_string(), origin.to_string()),
+    ];
+    if let Some(time_to_live) = frame.time_to_live {
+        fields.push(("time_to_live".to_string(), time_to_live.as_millis().to_string()));
+    }
+    fields
+}
+
+pub fn decode_frame(fields: &HashMap<String, String>) -> Result<TransformStamped, ErrorMsg> {
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .ok_or_else(|| ErrorMsg::new(&format!("Stored frame is missing the '{}' field.", name)))
+    };
+    let invalid = |name: &str, e: &dyn std::error::Error| {
+        ErrorMsg::new(&format!("Stored frame has an invalid '{}' field: '{}'.", name, e))
+    };
+
+    let time_stamp = field("time_stamp")?
+        .parse::<u64>()
+        .map_err(|e| invalid("time_stamp", &e))?;
+    let transform = serde_json::from_str::<JsonTransform>(field("transform")?)
+        .map_err(|e| invalid("transform", &e))?;
+    let frame_kind = serde_json::from_value::<FrameKind>(serde_json::Value::String(field("frame_kind")?.clone()))
+        .map_err(|e| invalid("frame_kind", &e))?;
+    let time_to_live = match fields.get("time_to_live") {
+        Some(millis) => Some(Duration::from_millis(
+            millis.parse::<u64>().map_err(|e| invalid("time_to_live", &e))?,
+        )),
+        None => None,
+    };
+
+    Ok(TransformStamped {
+        time_stamp: unix_micros_to_instant(time_stamp),
+        parent_frame_id: field("parent_frame_id")?.clone(),
+        child_frame_id: field("child_frame_id")?.clone(),
+        transform: json_transform_to_isometry(transform),
+        json_metadata: fields.get("json_metadata").cloned().unwrap_or_default(),
+        time_to_live,
+        frame_kind,
+    })
+}
+
+// Instants only mean something inside of one process, so they are stored as unix time
+fn instant_to_unix_micros(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let unix = match instant <= now {
+        true => unix_now.saturating_sub(now.saturating_duration_since(instant)),
+        false => unix_now + instant.saturating_duration_since(now),
+    };
+    unix.as_micros() as u64
+}
+
+fn unix_micros_to_instant(micros: u64) -> Instant {
+    let now = Instant::now();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let unix = Duration::from_micros(micros);
+    match unix <= unix_now {
+        true => now.checked_sub(unix_now - unix).unwrap_or(now),
+        false => now + (unix - unix_now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use nalgebra::{Isometry3, Vector3};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    use crate::*;
+
+    // The tests marked as ignored need a running redis-server, run them with
+    // 'cargo test -- --ignored', REDIS_URL can point to a server other than the local one
+    fn redis_url() -> String {
+        std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string())
+    }
+
+    fn test_namespace() -> String {
+        format!("space_tree_test_{:08x}", rand::random::<u32>())
+    }
+
+    fn frame(parent: &str, child: &str, x: f64) -> TransformStamped {
+        TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::new(Vector3::new(x, 0.0, 0.0), Vector3::z() * 0.5),
+            json_metadata: "{\"color\": \"red\"}".to_string(),
+            time_to_live: Some(Duration::from_millis(250)),
+            frame_kind: FrameKind::Dynamic,
+        }
+    }
+
+    #[test]
+    fn test_encode_and_decode_frame() {
+        let original = frame("world", "table", 1.5);
+        let fields = encode_frame(&original, "test").into_iter().collect::<HashMap<String, String>>();
+        assert_eq!(fields.get("frame_kind").unwrap(), "dynamic");
+        assert_eq!(fields.get("time_to_live").unwrap(), "250");
+
+        let decoded = decode_frame(&fields).unwrap();
+        assert_eq!(decoded.child_frame_id, original.child_frame_id);
+        assert_eq!(decoded.json_metadata, original.json_metadata);
+        assert_eq!(decoded.time_to_live, original.time_to_live);
+        assert!((decoded.transform.translation.vector - original.transform.translation.vector).norm() < 1e-9);
+        assert!(decoded.transform.rotation.angle_to(&original.transform.rotation) < 1e-9);
+        let drift = match decoded.time_stamp > original.time_stamp {
+            true => decoded.time_stamp - original.time_stamp,
+            false => original.time_stamp - decoded.time_stamp,
+        };
+        assert!(drift < Duration::from_millis(5));
+
+        let mut fields = fields;
+        fields.remove("transform");
+        assert!(decode_frame(&fields).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_redis_storage() {
+        let storage = RedisStorage::new(&redis_url(), &test_namespace(), HistoryConfig::default()).unwrap();
+        storage.put(&frame("world", "table", 1.0)).unwrap();
+        storage.put(&frame("world", "table", 2.0)).unwrap();
+        storage.put(&TransformStamped { time_to_live: None, ..frame("table", "part", 0.5) }).unwrap();
+
+        assert_eq!(storage.get("table").unwrap().unwrap().transform.translation.x, 2.0);
+        assert_eq!(storage.get("part").unwrap().unwrap().time_to_live, None);
+        assert_eq!(storage.history("table").unwrap().len(), 2);
+        assert_eq!(storage.frames().unwrap().len(), 2);
+
+        assert!(storage.remove("table").unwrap().is_some());
+        assert!(storage.remove("part").unwrap().is_some());
+        assert!(storage.get("table").unwrap().is_none());
+        assert!(storage.history("table").unwrap().is_empty());
+        assert!(storage.frames().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_trees_share_redis_storage() {
+        let namespace = test_namespace();
+        let storage = Arc::new(RedisStorage::new(&redis_url(), &namespace, HistoryConfig::default()).unwrap());
+        let first = SpaceTree::with_storage(TreeConfig::default(), storage.clone()).unwrap();
+        first
+            .insert(&vec![frame("world", "table", 1.0), frame("table", "part", 0.5)])
+            .await
+            .unwrap();
+
+        let (second, _) = load_tree_from_redis(&redis_url(), &namespace, TreeConfig::default()).unwrap();
+        let expected = first.lookup("world", "part").unwrap().transform;
+        let loaded = second.lookup("world", "part").unwrap().transform;
+        assert!((loaded.translation.vector - expected.translation.vector).norm() < 1e-9);
+
+        second.remove("table", RemovalMode::Cascade).unwrap();
+        assert!(storage.frames().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_follow_redis_changes() {
+        let namespace = test_namespace();
+        let (follower, storage) = load_tree_from_redis(&redis_url(), &namespace, TreeConfig::default()).unwrap();
+        let follower_clone = follower.clone();
+        tokio::spawn(async move { follow_redis_changes(&follower_clone, &storage).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (writer, _) = load_tree_from_redis(&redis_url(), &namespace, TreeConfig::default()).unwrap();
+        writer.insert(&vec![frame("world", "camera", 2.0)]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(follower.lookup("world", "camera").unwrap().transform.translation.x, 2.0);
+
+        writer.remove("camera", RemovalMode::Cascade).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!follower.contains("camera"));
+    }
+}
@@ -1,18 +1,20 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use crate::*;
 
 use termtree::Tree;
 
+#[allow(clippy::only_used_in_recursion)]
 pub fn build_tree_recursive(
     node_id: &str,
-    transforms: &HashMap<String, TransformStamped>,
+    transforms: &FrameTree,
     parent_map: &HashMap<String, Vec<String>>,
     current_depth: u64,
+    config: &TreeConfig,
 ) -> Tree<String> {
-    if current_depth > MAX_RECURSION_DEPTH {
+    if current_depth > config.max_chain_length {
         eprintln!("Maximum recursion depth reached for node ID {}", node_id);
         return Tree::new(format!("{} (depth limit reached)", node_id));
     }
@@ -23,7 +25,7 @@ pub fn build_tree_recursive(
         children.sort_unstable();
         for child_id in children {
             let child_tree =
-                build_tree_recursive(&child_id, transforms, parent_map, current_depth + 1);
+                build_tree_recursive(&child_id, transforms, parent_map, current_depth + 1, config);
             tree.push(child_tree);
         }
     }
@@ -31,30 +33,38 @@ pub fn build_tree_recursive(
     tree
 }
 
-pub fn get_tree_root(buffer: &HashMap<String, TransformStamped>) -> Option<String> {
-    for frame in buffer {
-        match buffer.get(&frame.0.clone()) {
-            Some(_) => continue,
-            None => return Some(frame.0.clone()),
-        }
-    }
-    None
+// The first root in alphabetical order, see find_roots for all of them
+pub fn get_tree_root(buffer: &FrameTree) -> Option<String> {
+    find_roots(buffer).into_iter().next()
+}
+
+// Children of every parent, taken from the index of the buffer
+pub fn build_parent_map(buffer: &FrameTree) -> HashMap<String, Vec<String>> {
+    buffer
+        .parent_ids()
+        .map(|parent| (parent.clone(), buffer.child_ids(parent).cloned().collect()))
+        .collect()
+}
+
+// The tree below the root, a forest is rendered one component at a time
+pub fn render_component(root: &str, buffer: &FrameTree, config: &TreeConfig) -> Tree<String> {
+    build_tree_recursive(root, buffer, &build_parent_map(buffer), 0, config)
 }
 
 pub async fn vizualize_tree(
-    buffer: &Arc<Mutex<HashMap<String, TransformStamped>>>,
+    buffer: &Arc<RwLock<FrameTree>>,
     refresh_rate: u64,
+    config: &TreeConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let buffer_local = buffer.lock().unwrap().clone();
-    let parent_map = HashMap::<String, Vec<String>>::new();
-
     loop {
-        match get_tree_root(&buffer_local) {
-            Some(root) => println!(
+        // Cloning the tree is cheap, the lock is not held while printing
+        let buffer_local = buffer.read().unwrap().clone();
+        let parent_map = build_parent_map(&buffer_local);
+        for root in find_roots(&buffer_local) {
+            println!(
                 "{}",
-                build_tree_recursive(&root, &buffer_local, &parent_map, 0)
-            ),
-            None => (),
+                build_tree_recursive(&root, &buffer_local, &parent_map, 0, config)
+            )
         }
 
         tokio::time::sleep(Duration::from_millis(refresh_rate)).await;
@@ -76,58 +86,53 @@ mod tests {
 
     #[test]
     fn test_build_tree_recursive() {
-        let mut transforms: HashMap<String, TransformStamped> = HashMap::new();
-        transforms.insert(
-            "child1".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "root".to_string(),
-                child_frame_id: "child1".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
-        transforms.insert(
-            "child2".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "child1".to_string(),
-                child_frame_id: "child2".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
-        transforms.insert(
-            "child3".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "child1".to_string(),
-                child_frame_id: "child3".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
-        transforms.insert(
-            "child5".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "child3".to_string(),
-                child_frame_id: "child5".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
-
-        transforms.insert(
-            "child4".to_string(),
-            TransformStamped {
-                time_stamp: Instant::now(),
-                parent_frame_id: "root".to_string(),
-                child_frame_id: "child4".to_string(),
-                transform: Isometry3::default(),
-                json_metadata: String::default(),
-            },
-        );
+        let mut transforms = FrameTree::new();
+        transforms.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "root".to_string(),
+            child_frame_id: "child1".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
+        transforms.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "child1".to_string(),
+            child_frame_id: "child2".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
+        transforms.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "child1".to_string(),
+            child_frame_id: "child3".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
+        transforms.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "child3".to_string(),
+            child_frame_id: "child5".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
+
+        transforms.insert(TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: "root".to_string(),
+            child_frame_id: "child4".to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        });
 
         let mut parent_map: HashMap<String, Vec<String>> = HashMap::new();
         for transform in transforms.values() {
@@ -138,15 +143,19 @@ mod tests {
         }
 
         if let Some(_) = parent_map.get("root") {
-            let tree = build_tree_recursive("root", &transforms, &parent_map, 0);
+            let tree = build_tree_recursive("root", &transforms, &parent_map, 0, &TreeConfig::default());
             assert_eq!(tree.to_string(), "root\n├── child1\n│   ├── child2\n│   └── child3\n│       └── child5\n└── child4\n")
         }
     }
 
     #[test]
     fn test_tree_maximum_recursion_depth() {
-        let mut transforms: HashMap<String, TransformStamped> = HashMap::new();
-        let max_depth = MAX_RECURSION_DEPTH + 1; // We exceed MAX_DEPTH to trigger the limit
+        let mut transforms = FrameTree::new();
+        let config = TreeConfig {
+            max_chain_length: 50,
+            ..TreeConfig::default()
+        };
+        let max_depth = config.max_chain_length + 1; // We exceed the limit to trigger it
         let parent_id_base = "node";
 
         // Create a linear hierarchy of nodes exceeding the maximum depth
@@ -158,16 +167,15 @@ mod tests {
             };
             let child_id = format!("{}{}", parent_id_base, i);
 
-            transforms.insert(
-                child_id.clone(),
-                TransformStamped {
-                    time_stamp: Instant::now(),
-                    parent_frame_id: parent_id,
-                    child_frame_id: child_id.clone(),
-                    transform: Isometry3::default(),
-                    json_metadata: "{}".to_string(),
-                },
-            );
+            transforms.insert(TransformStamped {
+                time_stamp: Instant::now(),
+                parent_frame_id: parent_id,
+                child_frame_id: child_id.clone(),
+                transform: Isometry3::default(),
+                json_metadata: "{}".to_string(),
+                time_to_live: None,
+                frame_kind: FrameKind::Dynamic,
+            });
         }
 
         let mut parent_map: HashMap<String, Vec<String>> = HashMap::new();
@@ -179,7 +187,7 @@ mod tests {
         }
 
         // Start the tree building from the root, which is the start of our chain
-        let tree = build_tree_recursive("root", &transforms, &parent_map, 0);
+        let tree = build_tree_recursive("root", &transforms, &parent_map, 0, &config);
 
         // Check for depth limit indication in the output
         let output = format!("{}", tree);
@@ -238,6 +246,29 @@ mod tests {
         println!("{}", tree);
     }
 
+    #[test]
+    fn test_render_forest() {
+        let frame = |parent: &str, child: &str| TransformStamped {
+            time_stamp: Instant::now(),
+            parent_frame_id: parent.to_string(),
+            child_frame_id: child.to_string(),
+            transform: Isometry3::default(),
+            json_metadata: String::default(),
+            time_to_live: None,
+            frame_kind: FrameKind::Dynamic,
+        };
+        let buffer = FrameTree::from_iter(vec![
+            frame("robot", "gripper"),
+            frame("rig", "camera_right"),
+            frame("rig", "camera_left"),
+        ]);
+
+        assert_eq!(get_tree_root(&buffer), Some("rig".to_string()));
+        assert_eq!(build_parent_map(&buffer)["rig"], vec!["camera_left", "camera_right"]);
+        assert_eq!(render_component("rig", &buffer, &TreeConfig::default()).to_string(), "rig\n├── camera_left\n└── camera_right\n");
+        assert_eq!(render_component("robot", &buffer, &TreeConfig::default()).to_string(), "robot\n└── gripper\n");
+    }
+
     // TODO: need a test for the async function
 
 }